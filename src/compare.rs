@@ -0,0 +1,66 @@
+use crate::error::ImageProcessError;
+use crate::metrics::{self, LumaPlane};
+use crate::options::{CompareMetric, CompareOptions};
+use image::{DynamicImage, GenericImageView};
+use std::path::Path;
+
+/// Compare two images for the `compare` subcommand.
+pub fn run(options: &CompareOptions) -> Result<(), ImageProcessError> {
+    let reference = open_image(&options.reference)?;
+    let candidate = open_image(&options.candidate)?;
+
+    if reference.dimensions() != candidate.dimensions() {
+        return Err(ImageProcessError::InvalidArguments(format!(
+            "Dimensions of the images differ: {}x{} vs {}x{}",
+            reference.width(),
+            reference.height(),
+            candidate.width(),
+            candidate.height()
+        )));
+    }
+
+    let (reference_rgba, candidate_rgba) = (reference.to_rgba8(), candidate.to_rgba8());
+    let psnr = metrics::psnr(&reference_rgba, &candidate_rgba);
+    let ssim = metrics::ssim(&LumaPlane::from(&reference), &LumaPlane::from(&candidate));
+    let max_diff = metrics::max_channel_diff(&reference_rgba, &candidate_rgba);
+
+    println!("Dimensions: {}x{}", reference.width(), reference.height());
+    println!("PSNR: {:.2} dB", psnr);
+    println!("SSIM: {:.4}", ssim);
+    println!(
+        "Max diff: R {}, G {}, B {}, A {}",
+        max_diff[0], max_diff[1], max_diff[2], max_diff[3]
+    );
+
+    if let Some(heatmap_path) = &options.heatmap {
+        metrics::diff_heatmap(&reference_rgba, &candidate_rgba).save(heatmap_path)?;
+        println!("Heatmap: {}", heatmap_path.display());
+    }
+
+    if let Some(threshold) = options.threshold {
+        let value = match options.metric {
+            CompareMetric::Ssim => ssim,
+            CompareMetric::Psnr => psnr,
+        };
+
+        if value < threshold {
+            return Err(ImageProcessError::BelowThreshold(format!(
+                "{} {:.4} is below {}",
+                options.metric, value, threshold
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn open_image(path: &Path) -> Result<DynamicImage, ImageProcessError> {
+    if !path.exists() {
+        return Err(ImageProcessError::InputNotFound(path.to_path_buf()));
+    }
+
+    // Decide the format by the content, the extension may lie.
+    Ok(image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?)
+}
@@ -0,0 +1,344 @@
+use crate::error::ValueParseError;
+use crate::options::CliOptions;
+use crate::preset::{self, Preset};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use structopt::clap::{ArgMatches, ErrorKind};
+use toml::{Table, Value};
+
+/// Name of the config file searched upward from the input dir.
+pub const CONFIG_FILE_NAME: &str = "imgtool.toml";
+
+/// Keys of the config file which are not options.
+const PRESET_KEY: &str = "preset";
+const PRESETS_KEY: &str = "presets";
+
+/// Args of one option, e.g. `("target-format", ["--target-format", "webp"])`.
+pub type OptionArgs = (String, Vec<String>);
+
+/// Parsed config file.
+pub struct Config {
+    pub path: PathBuf,
+    /// Option args sorted by key, `toml::Table` doesn't keep the order of the file.
+    pub options: Vec<OptionArgs>,
+    /// The preset selected by the `preset` key.
+    pub preset: Option<String>,
+    /// User defined presets in `[presets.<name>]` tables.
+    pub presets: Vec<Preset>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ValueParseError> {
+        let config_error = |msg: String| {
+            ValueParseError::new(format!("Invalid config file '{}': {}", path.display(), msg))
+        };
+
+        let content = fs::read_to_string(path).map_err(|e| config_error(e.to_string()))?;
+        let table: Table = content
+            .parse()
+            .map_err(|e: toml::de::Error| config_error(e.message().to_string()))?;
+
+        let mut config = Self {
+            path: path.to_path_buf(),
+            options: Vec::new(),
+            preset: None,
+            presets: Vec::new(),
+        };
+
+        for (key, value) in &table {
+            match key.as_str() {
+                PRESET_KEY => match value {
+                    Value::String(name) => config.preset = Some(name.clone()),
+                    _ => return Err(config_error("`preset` should be a string".to_string())),
+                },
+                PRESETS_KEY => {
+                    config.presets = preset::user_presets(value).map_err(config_error)?;
+                }
+                _ => {
+                    let name = key.replace('_', "-");
+                    if ["config", "input", "output"].contains(&name.as_str()) {
+                        return Err(config_error(format!(
+                            "`{}` can't be set in the config file",
+                            name
+                        )));
+                    }
+
+                    let args = to_args(&name, value).map_err(config_error)?;
+                    config.options.push((name, args));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The config file given with `--config`, or found upward from `start`.
+    pub fn locate(config: Option<&Path>, start: Option<&Path>) -> Option<PathBuf> {
+        match config {
+            Some(path) => Some(path.to_path_buf()),
+            None => start.and_then(find_config),
+        }
+    }
+}
+
+/// Prepend the settings of the preset and the config file to the command line args, the command
+/// line overrides both. Options turned off with `--no-<flag>` or conflicting with an option of
+/// higher precedence are not taken.
+///
+/// The config file is given with `--config`, or searched upward from the input dir. Keys of the
+/// config file are the long names of the options, e.g.
+///
+/// ```toml
+/// target-format = "webp"
+/// recursive = true
+/// exclude = ["icons/**"]
+///
+/// [jpeg-params]
+/// quality = 80
+/// ```
+pub fn with_config_args(args: Vec<OsString>) -> Result<Vec<OsString>, ValueParseError> {
+    // Errors of the command line are reported when parsing the merged args.
+    let Ok(matches) = CliOptions::clap().get_matches_from_safe(&args) else {
+        return Ok(args);
+    };
+
+    let config_path = Config::locate(
+        matches.value_of_os("config").map(Path::new),
+        matches.value_of_os("input").map(Path::new),
+    );
+    let config = match config_path {
+        Some(path) => Some(Config::load(&path)?),
+        None => None,
+    };
+
+    let cli_preset = matches.value_of("preset").map(str::to_string);
+    let preset = match cli_preset
+        .clone()
+        .or_else(|| config.as_ref().and_then(|config| config.preset.clone()))
+    {
+        Some(name) => {
+            let user_presets = config.as_ref().map_or(&[][..], |config| &config.presets);
+            let preset = preset::find(&name, user_presets).ok_or_else(|| {
+                ValueParseError::new(format!(
+                    "Unknown preset '{}', see `imgtool presets list`",
+                    name
+                ))
+            })?;
+            Some(preset)
+        }
+        None => None,
+    };
+
+    // Sources of options from high to low precedence, all below the command line. A preset
+    // selected on the command line overrides the config file, otherwise the config file
+    // overrides its preset.
+    let mut sources: Vec<(&[OptionArgs], String)> = Vec::new();
+    if let Some(config) = &config {
+        sources.push((
+            &config.options,
+            format!("config file '{}'", config.path.display()),
+        ));
+    }
+    if let Some(preset) = &preset {
+        let source = (&preset.options[..], format!("preset '{}'", preset.name));
+        if cli_preset.is_some() {
+            sources.insert(0, source);
+        } else {
+            sources.push(source);
+        }
+    }
+
+    let mut merged_args = args.clone();
+    let mut overridden: Vec<OptionArgs> = Vec::new();
+    for (options, source) in sources {
+        let option_args = override_args(options, &matches, &overridden, &merged_args);
+        merged_args.splice(1..1, option_args);
+        check_args(&merged_args)
+            .map_err(|msg| ValueParseError::new(format!("Invalid {}: {}", source, msg)))?;
+        overridden.extend(options.iter().cloned());
+    }
+
+    // Pass on the config file found, so it's known to be skipped as an input file.
+    if let Some(config) = &config
+        && matches.occurrences_of("config") == 0
+    {
+        merged_args.push(OsString::from("--config"));
+        merged_args.push(config.path.clone().into_os_string());
+    }
+
+    Ok(merged_args)
+}
+
+/// Args of `options` which are neither given on the command line nor in `overrides`, also the
+/// options turned off there with `--no-<flag>` or conflicting with the `merged_args` are dropped,
+/// e.g. `recursive` by `--no-recursive`, or `max-size` by `--min-ssim`.
+fn override_args(
+    options: &[OptionArgs],
+    matches: &ArgMatches,
+    overrides: &[OptionArgs],
+    merged_args: &[OsString],
+) -> Vec<OsString> {
+    options
+        .iter()
+        .filter(|(name, _)| {
+            let counterpart = counterpart(name);
+            matches.occurrences_of(name) == 0 && matches.occurrences_of(&counterpart) == 0
+        })
+        .filter(|(name, _)| {
+            !overrides
+                .iter()
+                .any(|(other, _)| other == name || *other == counterpart(name))
+        })
+        .filter(|(_, args)| !conflicts_with(merged_args, args))
+        .flat_map(|(_, args)| args.iter().map(OsString::from))
+        .collect()
+}
+
+/// The `--no-` counterpart of a flag, or the flag of a `--no-` one.
+fn counterpart(name: &str) -> String {
+    match name.strip_prefix("no-") {
+        Some(name) => name.to_string(),
+        None => format!("no-{}", name),
+    }
+}
+
+/// Whether the option args conflict with an option of `args`.
+fn conflicts_with(args: &[OsString], option_args: &[String]) -> bool {
+    let mut merged_args = args.to_vec();
+    merged_args.splice(1..1, option_args.iter().map(OsString::from));
+
+    matches!(
+        CliOptions::clap().get_matches_from_safe(merged_args),
+        Err(e) if e.kind == ErrorKind::ArgumentConflict
+    )
+}
+
+/// Parse the merged args, the command line alone is valid, so an error comes from the args
+/// added before it.
+fn check_args(args: &[OsString]) -> Result<(), String> {
+    match CliOptions::from_iter_safe(args) {
+        Err(e) if e.use_stderr() => Err(e
+            .message
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches("error: ")
+            .to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Find the config file in the dir of `input` or its ancestors.
+fn find_config(input: &Path) -> Option<PathBuf> {
+    let input = input.canonicalize().ok()?;
+    let start_dir = if input.is_dir() {
+        input.as_path()
+    } else {
+        input.parent()?
+    };
+
+    start_dir
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// Convert a config entry to command line args.
+///
+/// Booleans are flags, arrays are repeated options, and tables are joined as `key=value,...` like
+/// `--jpeg-params`. The `rule` key of `[resize-args]` is the rule before `:`.
+pub fn to_args(name: &str, value: &Value) -> Result<Vec<String>, String> {
+    let option = format!("--{}", name);
+
+    match value {
+        Value::Boolean(true) => Ok(vec![option]),
+        Value::Boolean(false) => Ok(vec![]),
+        Value::Array(values) => {
+            let mut args = Vec::new();
+            for value in values {
+                args.push(option.clone());
+                args.push(to_scalar(name, value)?);
+            }
+            Ok(args)
+        }
+        Value::Table(table) => {
+            let mut rule = None;
+            let mut pairs = Vec::new();
+            for (key, value) in table {
+                let value = to_scalar(name, value)?;
+                if name == "resize-args" && key == "rule" {
+                    rule = Some(value);
+                } else {
+                    pairs.push(format!("{}={}", key, value));
+                }
+            }
+
+            let params = pairs.join(",");
+            match rule {
+                Some(rule) => Ok(vec![option, format!("{}:{}", rule, params)]),
+                None => Ok(vec![option, params]),
+            }
+        }
+        value => Ok(vec![option, to_scalar(name, value)?]),
+    }
+}
+
+fn to_scalar(name: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!("Invalid value of `{}`: {}", name, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Merged args of the command line `args` with the config file `content`.
+    fn merged_args(name: &str, content: &str, args: &[&str]) -> Vec<String> {
+        let path =
+            std::env::temp_dir().join(format!("imgtool-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+
+        let mut cli_args = vec!["imgtool", "-i", "in", "-o", "out", "--config"];
+        let path_arg = path.to_string_lossy().to_string();
+        cli_args.push(&path_arg);
+        cli_args.extend(args);
+        let merged = with_config_args(cli_args.iter().map(OsString::from).collect());
+        fs::remove_file(&path).unwrap();
+
+        merged
+            .unwrap()
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn command_line_overrides_config_options() {
+        let args = merged_args(
+            "override",
+            "recursive = true\ntarget-format = \"webp\"\n",
+            &["--no-recursive", "-t", "png"],
+        );
+        assert!(!args.contains(&"--recursive".to_string()));
+        assert!(!args.contains(&"webp".to_string()));
+    }
+
+    #[test]
+    fn config_options_conflicting_with_command_line_are_dropped() {
+        let args = merged_args(
+            "conflict",
+            "max-size = \"200KB\"\nlossless = true\n",
+            &["--min-ssim", "0.9"],
+        );
+        assert!(!args.contains(&"--max-size".to_string()));
+        assert!(args.contains(&"--lossless".to_string()));
+        assert!(CliOptions::from_iter_safe(&args).is_ok());
+    }
+}
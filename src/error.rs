@@ -1,35 +1,241 @@
-use std::{error, fmt, io};
+use caesium::error::CaesiumError;
 use std::num::{ParseFloatError, ParseIntError};
+use std::path::PathBuf;
 use std::str::ParseBoolError;
+use std::{error, fmt, io};
 
 #[derive(Debug)]
-pub struct ImageProcessError {
-    msg: String,
+pub enum ImageProcessError {
+    /// The input file or dir does not exist.
+    InputNotFound(PathBuf),
+    /// Invalid or conflicting arguments.
+    InvalidArguments(String),
+    /// The input image can't be read or decoded.
+    Decode(String),
+    /// The output image can't be compressed, converted or encoded.
+    Encode(String),
+    Io(io::Error),
+    /// Some files of a batch failed while `continue_on_error` is set.
+    PartialFailure {
+        failed: usize,
+        total: usize,
+    },
+    /// The metric of `compare` is below `--threshold`.
+    BelowThreshold(String),
 }
 
 impl ImageProcessError {
-    pub fn new(msg: String) -> Self {
-        Self { msg }
+    /// Process exit code of the error, `1` is left for unexpected failures.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::InvalidArguments(_) => 2,
+            Self::InputNotFound(_) => 3,
+            Self::Decode(_) => 4,
+            Self::Encode(_) => 5,
+            Self::Io(_) => 6,
+            Self::PartialFailure { .. } => 7,
+            Self::BelowThreshold(_) => 8,
+        }
     }
 }
 
-impl error::Error for ImageProcessError {}
+impl error::Error for ImageProcessError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for ImageProcessError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "IOError: {}", self.msg)
+        match self {
+            Self::InputNotFound(path) => {
+                write!(
+                    f,
+                    "InputNotFound: File or dir not exists: {}",
+                    path.display()
+                )
+            }
+            Self::InvalidArguments(msg) => write!(f, "InvalidArguments: {}", msg),
+            Self::Decode(msg) => write!(f, "DecodeError: {}", msg),
+            Self::Encode(msg) => write!(f, "EncodeError: {}", msg),
+            Self::Io(err) => write!(f, "IOError: {}", err),
+            Self::PartialFailure { failed, total } => {
+                write!(f, "PartialFailure: {} of {} file(s) failed", failed, total)
+            }
+            Self::BelowThreshold(msg) => write!(f, "BelowThreshold: {}", msg),
+        }
     }
 }
 
 impl From<io::Error> for ImageProcessError {
     fn from(err: io::Error) -> Self {
-        Self::new(format!("{}", err))
+        Self::Io(err)
+    }
+}
+
+impl From<CaesiumError> for ImageProcessError {
+    fn from(err: CaesiumError) -> Self {
+        let diagnostic = CaesiumDiagnostic::from(&err);
+        let msg = diagnostic.to_string();
+
+        match diagnostic.category {
+            CaesiumErrorCategory::InvalidParameters => Self::InvalidArguments(msg),
+            CaesiumErrorCategory::UnsupportedFormat | CaesiumErrorCategory::CorruptInput => {
+                Self::Decode(msg)
+            }
+            CaesiumErrorCategory::Io => Self::Io(io::Error::other(msg)),
+            CaesiumErrorCategory::ConversionNotPossible
+            | CaesiumErrorCategory::TargetNotReached
+            | CaesiumErrorCategory::EncodeFailure => Self::Encode(msg),
+        }
+    }
+}
+
+/// Category of a libcaesium error, decided by its error code.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CaesiumErrorCategory {
+    /// Quality or optimization parameters out of range.
+    InvalidParameters,
+    /// The image format can't be processed by libcaesium.
+    UnsupportedFormat,
+    /// The input image data is broken and can't be decoded.
+    CorruptInput,
+    /// The image can't be converted to the target format.
+    ConversionNotPossible,
+    /// The requested output size can't be reached.
+    TargetNotReached,
+    /// Reading or writing files inside libcaesium failed.
+    Io,
+    /// Compressing or encoding the output failed.
+    EncodeFailure,
+}
+
+impl From<u32> for CaesiumErrorCategory {
+    fn from(code: u32) -> Self {
+        match code {
+            10001..=10006 | 20401..=20404 => Self::InvalidParameters,
+            10000 | 10200 | 10400 | 20400 | 20506 => Self::UnsupportedFormat,
+            10300 | 10301 | 10401..=10403 | 20104 | 20204 | 20304 | 20306 | 20504 => {
+                Self::CorruptInput
+            }
+            10404 | 10406 | 10407 => Self::ConversionNotPossible,
+            10201 | 10202 => Self::TargetNotReached,
+            10203
+            | 10204
+            | 10410..=10413
+            | 20100..=20102
+            | 20200..=20203
+            | 20300..=20303
+            | 20406..=20409
+            | 20500..=20503 => Self::Io,
+            _ => Self::EncodeFailure,
+        }
     }
 }
 
-impl From<caesium::error::CaesiumError> for ImageProcessError {
-    fn from(err: caesium::error::CaesiumError) -> Self {
-        Self::new(format!("{}", err))
+impl fmt::Display for CaesiumErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::InvalidParameters => "invalid parameters",
+            Self::UnsupportedFormat => "unsupported format",
+            Self::CorruptInput => "corrupt input",
+            Self::ConversionNotPossible => "conversion not possible",
+            Self::TargetNotReached => "target not reached",
+            Self::Io => "io error",
+            Self::EncodeFailure => "encode failure",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Human readable diagnostic of a libcaesium error.
+pub struct CaesiumDiagnostic<'a> {
+    pub category: CaesiumErrorCategory,
+    /// What the user can do about the error, if known.
+    pub hint: Option<&'static str>,
+    error: &'a CaesiumError,
+}
+
+impl<'a> From<&'a CaesiumError> for CaesiumDiagnostic<'a> {
+    fn from(error: &'a CaesiumError) -> Self {
+        let code = Self::effective_code(error);
+        let hint = match code {
+            10001..=10006 => Some("check the quality and optimization options"),
+            10200 => Some("GIF images can't be compressed in memory, choose another target format"),
+            20400 => Some("GIF images can't be resized, drop the resize options"),
+            20506 => Some("the color type of this TIFF image is not supported"),
+            10300 | 10301 | 10401..=10403 | 20104 | 20204 | 20304 | 20306 | 20504 => {
+                Some("the file may be truncated or damaged, try to re-export it")
+            }
+            10404 => Some(
+                "the image can't be encoded as the target format, try another `--target-format`",
+            ),
+            10406 | 10407 => Some("the image is already in the target format"),
+            10201 | 10202 => Some("raise `--max-size` or add `--shrink-to-fit`"),
+            _ => None,
+        };
+
+        Self {
+            category: CaesiumErrorCategory::from(code),
+            hint,
+            error,
+        }
+    }
+}
+
+impl CaesiumDiagnostic<'_> {
+    /// Code 10405 wraps the error of compressing a converted image as "message [code]",
+    /// the wrapped code tells more about what went wrong.
+    fn effective_code(error: &CaesiumError) -> u32 {
+        if error.code != 10405 {
+            return error.code;
+        }
+
+        error
+            .message
+            .strip_suffix(']')
+            .and_then(|message| message.rsplit_once('['))
+            .and_then(|(_, code)| code.parse().ok())
+            .unwrap_or(error.code)
+    }
+
+    /// Whether compressing the image in its origin format is a safe fallback of a failed conversion.
+    pub fn can_fallback_to_compress(&self) -> bool {
+        matches!(self.error.code, 10406 | 10407)
+    }
+}
+
+impl fmt::Display for CaesiumDiagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.category)?;
+        if let Some(hint) = self.hint {
+            write!(f, ", {}", hint)?;
+        }
+        write!(f, " ({}, code {})", self.error.message, self.error.code)
+    }
+}
+
+impl From<imagesize::ImageError> for ImageProcessError {
+    fn from(err: imagesize::ImageError) -> Self {
+        Self::Decode(format!("{}", err))
+    }
+}
+
+impl From<image::ImageError> for ImageProcessError {
+    fn from(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(err) => Self::Io(err),
+            err => Self::Decode(format!("{}", err)),
+        }
+    }
+}
+
+impl From<ValueParseError> for ImageProcessError {
+    fn from(err: ValueParseError) -> Self {
+        Self::InvalidArguments(err.msg)
     }
 }
 
@@ -83,3 +289,76 @@ impl From<ParseFloatError> for ValueParseError {
         Self::new(format!("ParseFloatErr: {}", err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caesium_error(code: u32, message: &str) -> CaesiumError {
+        CaesiumError {
+            message: message.to_string(),
+            code,
+        }
+    }
+
+    #[test]
+    fn error_codes_map_to_categories() {
+        let cases = [
+            (10001, CaesiumErrorCategory::InvalidParameters),
+            (20404, CaesiumErrorCategory::InvalidParameters),
+            (10200, CaesiumErrorCategory::UnsupportedFormat),
+            (20506, CaesiumErrorCategory::UnsupportedFormat),
+            (10301, CaesiumErrorCategory::CorruptInput),
+            (20304, CaesiumErrorCategory::CorruptInput),
+            (10404, CaesiumErrorCategory::ConversionNotPossible),
+            (10407, CaesiumErrorCategory::ConversionNotPossible),
+            (10201, CaesiumErrorCategory::TargetNotReached),
+            (10411, CaesiumErrorCategory::Io),
+            (20502, CaesiumErrorCategory::Io),
+            (20105, CaesiumErrorCategory::EncodeFailure),
+        ];
+
+        for (code, category) in cases {
+            assert_eq!(CaesiumErrorCategory::from(code), category, "code {}", code);
+        }
+    }
+
+    #[test]
+    fn wrapped_error_code_decides_the_category() {
+        let error = caesium_error(10405, "Invalid JPEG data [10301]");
+        let diagnostic = CaesiumDiagnostic::from(&error);
+        assert_eq!(diagnostic.category, CaesiumErrorCategory::CorruptInput);
+        assert!(diagnostic.hint.is_some());
+
+        let error = caesium_error(10405, "Cannot convert");
+        let diagnostic = CaesiumDiagnostic::from(&error);
+        assert_eq!(diagnostic.category, CaesiumErrorCategory::EncodeFailure);
+    }
+
+    #[test]
+    fn categories_map_to_error_variants() {
+        let err = ImageProcessError::from(caesium_error(10002, "Invalid quality"));
+        assert!(matches!(err, ImageProcessError::InvalidArguments(_)));
+        assert_eq!(err.exit_code(), 2);
+
+        let err = ImageProcessError::from(caesium_error(10300, "Broken image"));
+        assert!(matches!(err, ImageProcessError::Decode(_)));
+
+        let err = ImageProcessError::from(caesium_error(10410, "Cannot write"));
+        assert!(matches!(err, ImageProcessError::Io(_)));
+
+        let err = ImageProcessError::from(caesium_error(10202, "Cannot reach size"));
+        assert!(matches!(err, ImageProcessError::Encode(_)));
+        assert_eq!(err.exit_code(), 5);
+    }
+
+    #[test]
+    fn diagnostic_shows_category_hint_and_origin_message() {
+        let error = caesium_error(10404, "Cannot convert to GIF");
+        assert_eq!(
+            CaesiumDiagnostic::from(&error).to_string(),
+            "conversion not possible, the image can't be encoded as the target format, \
+             try another `--target-format` (Cannot convert to GIF, code 10404)"
+        );
+    }
+}
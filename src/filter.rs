@@ -0,0 +1,102 @@
+use crate::error::ValueParseError;
+use crate::options::CliOptions;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+/// Select input files of batch process by include / exclude globs and extensions.
+pub struct InputFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    ext: Option<GlobSet>,
+}
+
+impl TryFrom<&CliOptions> for InputFilter {
+    type Error = ValueParseError;
+
+    fn try_from(options: &CliOptions) -> Result<Self, Self::Error> {
+        let mut ext_globs = Vec::new();
+        for ext in &options.ext {
+            let ext = ext.trim().trim_start_matches('.');
+            if ext.is_empty() {
+                continue;
+            }
+
+            let glob = GlobBuilder::new(&format!("*.{}", ext))
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| ValueParseError::new(format!("Invalid extension '{}': {}", ext, e)))?;
+            ext_globs.push(glob);
+        }
+
+        Ok(Self {
+            include: Self::build_glob_set(&options.include)?,
+            exclude: Self::build_glob_set(&options.exclude)?,
+            ext: Self::build_glob_set(&ext_globs)?,
+        })
+    }
+}
+
+impl InputFilter {
+    fn build_glob_set(globs: &[Glob]) -> Result<Option<GlobSet>, ValueParseError> {
+        if globs.is_empty() {
+            return Ok(None);
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(glob.clone());
+        }
+
+        builder
+            .build()
+            .map(Some)
+            .map_err(|e| ValueParseError::new(e.to_string()))
+    }
+
+    /// Whether the file should be processed, `relative_path` is the path relative to the input dir.
+    /// The file must match both the include globs and the extensions if given.
+    pub fn is_match(&self, relative_path: &Path) -> bool {
+        let matches = |glob_set: &Option<GlobSet>| {
+            glob_set
+                .as_ref()
+                .is_none_or(|glob_set| glob_set.is_match(relative_path))
+        };
+        if !matches(&self.include) || !matches(&self.ext) {
+            return false;
+        }
+
+        match &self.exclude {
+            Some(exclude) => !exclude.is_match(relative_path),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn input_filter(args: &[&str]) -> InputFilter {
+        let options =
+            CliOptions::from_iter(["imgtool", "-i", "in", "-o", "out"].iter().chain(args));
+        InputFilter::try_from(&options).unwrap()
+    }
+
+    #[test]
+    fn ext_narrows_include() {
+        let filter = input_filter(&["--include", "icons/**", "--ext", "png"]);
+        assert!(filter.is_match(Path::new("icons/a.png")));
+        assert!(filter.is_match(Path::new("icons/b.PNG")));
+        assert!(!filter.is_match(Path::new("icons/a.jpg")));
+        assert!(!filter.is_match(Path::new("photos/a.png")));
+    }
+
+    #[test]
+    fn exclude_wins_over_include_and_ext() {
+        let filter = input_filter(&["--ext", ".png,jpg", "--exclude", "*.min.*"]);
+        assert!(filter.is_match(Path::new("a.jpg")));
+        assert!(!filter.is_match(Path::new("a.min.png")));
+        assert!(!filter.is_match(Path::new("a.gif")));
+    }
+}
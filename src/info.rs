@@ -0,0 +1,197 @@
+use crate::error::ImageProcessError;
+use crate::options::InfoOptions;
+use crate::report::format_bytes;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, ImageDecoder, ImageFormat, ImageReader, ImageResult};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Information of one image file, printed by the `info` subcommand.
+///
+/// Fields besides format, dimensions and file size are `None` if the image can't be decoded,
+/// e.g. the format is only recognized by `imagesize`.
+#[derive(Serialize)]
+struct ImageInfo {
+    path: String,
+    format: String,
+    width: usize,
+    height: usize,
+    color_type: Option<String>,
+    bit_depth: Option<u16>,
+    has_alpha: Option<bool>,
+    icc_profile_bytes: Option<usize>,
+    has_exif: Option<bool>,
+    has_xmp: Option<bool>,
+    orientation: Option<u8>,
+    frames: Option<usize>,
+    file_size: u64,
+}
+
+impl ImageInfo {
+    fn new(path: &Path) -> Result<Self, ImageProcessError> {
+        if !path.exists() {
+            return Err(ImageProcessError::InputNotFound(path.to_path_buf()));
+        }
+
+        let data = fs::read(path)?;
+        let image_type = imagesize::image_type(&data)
+            .map_err(|_| ImageProcessError::Decode("not an image".to_string()))?;
+        let size = imagesize::blob_size(&data)?;
+
+        let mut info = Self {
+            path: path.to_string_lossy().to_string(),
+            format: format!("{:?}", image_type).to_ascii_lowercase(),
+            width: size.width,
+            height: size.height,
+            color_type: None,
+            bit_depth: None,
+            has_alpha: None,
+            icc_profile_bytes: None,
+            has_exif: None,
+            has_xmp: None,
+            orientation: None,
+            frames: None,
+            file_size: data.len() as u64,
+        };
+
+        // Formats unknown to the image crate keep the basic information only.
+        let reader = ImageReader::new(Cursor::new(&data)).with_guessed_format()?;
+        if let Some(format) = reader.format()
+            && format.reading_enabled()
+        {
+            info.read_details(reader.into_decoder()?)?;
+            info.frames = Some(Self::count_frames(&data, format)?);
+        }
+
+        Ok(info)
+    }
+
+    fn read_details(&mut self, mut decoder: impl ImageDecoder) -> ImageResult<()> {
+        let color_type = decoder.original_color_type();
+        self.color_type = Some(format!("{:?}", color_type));
+        self.bit_depth = Some(color_type.bits_per_pixel() / u16::from(color_type.channel_count()));
+        self.has_alpha = Some(decoder.color_type().has_alpha());
+        self.icc_profile_bytes = Some(decoder.icc_profile()?.map_or(0, |icc| icc.len()));
+        self.has_exif = Some(decoder.exif_metadata()?.is_some());
+        self.has_xmp = Some(decoder.xmp_metadata()?.is_some());
+        self.orientation = Some(decoder.orientation()?.to_exif());
+        Ok(())
+    }
+
+    /// Count frames of animated GIF, WebP and PNG, other images have one frame.
+    fn count_frames(data: &[u8], format: ImageFormat) -> ImageResult<usize> {
+        let reader = Cursor::new(data);
+
+        match format {
+            ImageFormat::Gif => Ok(GifDecoder::new(reader)?.into_frames().count()),
+            ImageFormat::WebP => {
+                let decoder = WebPDecoder::new(reader)?;
+                if decoder.has_animation() {
+                    Ok(decoder.into_frames().count())
+                } else {
+                    Ok(1)
+                }
+            }
+            ImageFormat::Png => {
+                let decoder = PngDecoder::new(reader)?;
+                if decoder.is_apng()? {
+                    Ok(decoder.apng()?.into_frames().count())
+                } else {
+                    Ok(1)
+                }
+            }
+            _ => Ok(1),
+        }
+    }
+}
+
+impl fmt::Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |v: Option<bool>| match v {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "unknown",
+        };
+        let or_unknown = |v: Option<String>| v.unwrap_or("unknown".to_string());
+
+        writeln!(f, "{}", self.path)?;
+        writeln!(f, "    format: {}", self.format)?;
+        writeln!(f, "    dimensions: {}x{}", self.width, self.height)?;
+        writeln!(f, "    color type: {}", or_unknown(self.color_type.clone()))?;
+        writeln!(
+            f,
+            "    bit depth: {}",
+            or_unknown(self.bit_depth.map(|v| v.to_string()))
+        )?;
+        writeln!(f, "    alpha: {}", yes_no(self.has_alpha))?;
+        writeln!(
+            f,
+            "    icc profile: {}",
+            match self.icc_profile_bytes {
+                Some(0) => "no".to_string(),
+                Some(bytes) => format!("yes ({} bytes)", bytes),
+                None => "unknown".to_string(),
+            }
+        )?;
+        writeln!(f, "    exif: {}", yes_no(self.has_exif))?;
+        writeln!(f, "    xmp: {}", yes_no(self.has_xmp))?;
+        writeln!(
+            f,
+            "    orientation: {}",
+            or_unknown(self.orientation.map(|v| v.to_string()))
+        )?;
+        writeln!(
+            f,
+            "    frames: {}",
+            or_unknown(self.frames.map(|v| v.to_string()))
+        )?;
+        write!(
+            f,
+            "    file size: {} ({} bytes)",
+            format_bytes(self.file_size),
+            self.file_size
+        )
+    }
+}
+
+/// Print information of images for the `info` subcommand. Files that can't be inspected are
+/// reported and skipped.
+pub fn run(options: &InfoOptions) -> Result<(), ImageProcessError> {
+    let total = options.files.len();
+    let mut infos = Vec::new();
+    let mut failed = 0;
+
+    for file in &options.files {
+        match ImageInfo::new(file) {
+            Ok(info) => {
+                if !options.json {
+                    println!("{}", info);
+                }
+                infos.push(info);
+            }
+            Err(err) if total == 1 => return Err(err),
+            Err(err) => {
+                eprintln!("'{}': {}", file.display(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    if options.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&infos).map_err(std::io::Error::from)?
+        );
+    }
+
+    if failed > 0 {
+        return Err(ImageProcessError::PartialFailure { failed, total });
+    }
+
+    Ok(())
+}
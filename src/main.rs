@@ -1,23 +1,57 @@
+mod compare;
+mod config;
 mod error;
+mod filter;
+mod info;
+mod metrics;
 mod options;
+mod preset;
+mod report;
 mod runner;
+mod transform;
 
-use crate::options::CliOptions;
+use crate::error::ImageProcessError;
+use crate::options::{CliOptions, PresetsCommand, SubCommand};
 use runner::{RunConfiguration, Runner};
+use std::ffi::OsString;
+use std::{env, process};
 use structopt::StructOpt;
 
 fn main() {
-    let cli_opt: CliOptions = CliOptions::from_args();
-    let run_configuration = RunConfiguration::try_from(cli_opt);
-    if let Err(e) = run_configuration {
-        eprintln!("{}", e);
-        return;
-    }
+    let args: Vec<OsString> = env::args_os().collect();
+
+    let result = if SubCommand::is_present(&args) {
+        match parse_args::<SubCommand>(args) {
+            SubCommand::Compare(options) => compare::run(&options),
+            SubCommand::Info(options) => info::run(&options),
+            SubCommand::Presets(PresetsCommand::List(options)) => preset::list(&options),
+        }
+    } else {
+        config::with_config_args(args)
+            .map_err(ImageProcessError::from)
+            .and_then(|args| run(parse_args(args)))
+    };
 
-    let run_configuration = run_configuration.unwrap();
-    let runner = Runner::from(run_configuration);
-    let result = runner.run();
     if let Err(e) = result {
         eprintln!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+fn parse_args<T: StructOpt>(args: Vec<OsString>) -> T {
+    match T::from_iter_safe(args) {
+        Ok(parsed) => parsed,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            process::exit(ImageProcessError::InvalidArguments(e.message).exit_code());
+        }
+        // --help or --version
+        Err(e) => e.exit(),
     }
 }
+
+fn run(cli_opt: CliOptions) -> Result<(), ImageProcessError> {
+    let run_configuration = RunConfiguration::try_from(cli_opt)?;
+    let runner = Runner::from(run_configuration);
+    runner.run()
+}
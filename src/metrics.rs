@@ -0,0 +1,162 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage, RgbaImage};
+
+/// Side of the square windows SSIM is computed over.
+const SSIM_WINDOW: usize = 8;
+/// Distance between two neighbouring SSIM windows.
+const SSIM_STEP: usize = 4;
+/// Stabilizing constants of SSIM, `(0.01 * 255)^2` and `(0.03 * 255)^2`.
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+
+/// Luma channel of an image, transparent pixels are blended over white.
+pub struct LumaPlane {
+    width: usize,
+    height: usize,
+    pixels: Vec<f64>,
+}
+
+impl From<&DynamicImage> for LumaPlane {
+    fn from(image: &DynamicImage) -> Self {
+        let (width, height) = image.dimensions();
+        let pixels = image
+            .to_rgba8()
+            .pixels()
+            .map(|p| {
+                let [r, g, b, a] = p.0.map(f64::from);
+                let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+                luma * a / 255.0 + 255.0 * (1.0 - a / 255.0)
+            })
+            .collect();
+
+        Self {
+            width: width as usize,
+            height: height as usize,
+            pixels,
+        }
+    }
+}
+
+impl LumaPlane {
+    /// Luma of `image` resized to `width`x`height`, used to compare with a resized output.
+    pub fn resized(image: &DynamicImage, width: u32, height: u32) -> Self {
+        if image.dimensions() == (width, height) {
+            Self::from(image)
+        } else {
+            Self::from(&image.resize_exact(width, height, FilterType::Triangle))
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width as u32, self.height as u32)
+    }
+
+    fn window_stats(&self, other: &Self, x: usize, y: usize, size: (usize, usize)) -> f64 {
+        let count = (size.0 * size.1) as f64;
+        let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+
+        for row in y..y + size.1 {
+            for col in x..x + size.0 {
+                let a = self.pixels[row * self.width + col];
+                let b = other.pixels[row * other.width + col];
+                sum_a += a;
+                sum_b += b;
+                sum_aa += a * a;
+                sum_bb += b * b;
+                sum_ab += a * b;
+            }
+        }
+
+        let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+        let var_a = sum_aa / count - mean_a * mean_a;
+        let var_b = sum_bb / count - mean_b * mean_b;
+        let covariance = sum_ab / count - mean_a * mean_b;
+
+        ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+            / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2))
+    }
+}
+
+/// Mean SSIM of two luma planes of the same size, `1.0` means identical.
+pub fn ssim(reference: &LumaPlane, candidate: &LumaPlane) -> f64 {
+    debug_assert_eq!(reference.dimensions(), candidate.dimensions());
+
+    // Images smaller than a window are compared as a whole.
+    let window = (
+        SSIM_WINDOW.min(reference.width),
+        SSIM_WINDOW.min(reference.height),
+    );
+    if window.0 == 0 || window.1 == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut count = 0;
+    for y in (0..=reference.height - window.1).step_by(SSIM_STEP) {
+        for x in (0..=reference.width - window.0).step_by(SSIM_STEP) {
+            total += reference.window_stats(candidate, x, y, window);
+            count += 1;
+        }
+    }
+
+    total / count as f64
+}
+
+/// Pixel channels with alpha blended over white, so that transparent pixels compare equal no
+/// matter what color they hide.
+fn blended_rgb(pixel: [u8; 4]) -> [f64; 3] {
+    let alpha = f64::from(pixel[3]) / 255.0;
+    [0, 1, 2].map(|i| f64::from(pixel[i]) * alpha + 255.0 * (1.0 - alpha))
+}
+
+/// PSNR in dB over the RGB channels of two images of the same size, infinite if identical.
+pub fn psnr(reference: &RgbaImage, candidate: &RgbaImage) -> f64 {
+    debug_assert_eq!(reference.dimensions(), candidate.dimensions());
+
+    let mut squared_error = 0.0;
+    for (a, b) in reference.pixels().zip(candidate.pixels()) {
+        let (a, b) = (blended_rgb(a.0), blended_rgb(b.0));
+        for i in 0..3 {
+            squared_error += (a[i] - b[i]) * (a[i] - b[i]);
+        }
+    }
+
+    let samples = (reference.width() as f64) * (reference.height() as f64) * 3.0;
+    if squared_error == 0.0 || samples == 0.0 {
+        return f64::INFINITY;
+    }
+
+    let mse = squared_error / samples;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Max absolute difference of each RGBA channel.
+pub fn max_channel_diff(reference: &RgbaImage, candidate: &RgbaImage) -> [u8; 4] {
+    let mut max_diff = [0; 4];
+    for (a, b) in reference.pixels().zip(candidate.pixels()) {
+        for (i, diff) in max_diff.iter_mut().enumerate() {
+            *diff = (*diff).max(a.0[i].abs_diff(b.0[i]));
+        }
+    }
+    max_diff
+}
+
+/// Heatmap of the max channel difference of each pixel, scaled so that the biggest difference in
+/// the image is white: black -> red -> yellow -> white.
+pub fn diff_heatmap(reference: &RgbaImage, candidate: &RgbaImage) -> RgbImage {
+    let diffs: Vec<u8> = reference
+        .pixels()
+        .zip(candidate.pixels())
+        .map(|(a, b)| (0..4).map(|i| a.0[i].abs_diff(b.0[i])).max().unwrap_or(0))
+        .collect();
+    let max_diff = diffs.iter().copied().max().unwrap_or(0).max(1);
+
+    let mut heatmap = RgbImage::new(reference.width(), reference.height());
+    for (pixel, diff) in heatmap.pixels_mut().zip(diffs) {
+        let level = u32::from(diff) * 765 / u32::from(max_diff);
+        let channel = |offset: u32| level.saturating_sub(offset).min(255) as u8;
+        *pixel = Rgb([channel(0), channel(255), channel(510)]);
+    }
+
+    heatmap
+}
@@ -1,10 +1,11 @@
 use crate::error::ValueParseError;
-use caesium;
 use caesium::parameters as cs_params;
+use globset::Glob;
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::num::ParseIntError;
 use std::path::PathBuf;
-use std::str;
+use std::{fmt, str};
 use structopt::StructOpt;
 
 fn parse_kv(input: &str) -> HashMap<&str, &str> {
@@ -41,12 +42,40 @@ impl str::FromStr for OutputFormatTypes {
             "png" => Ok(Self::Png),
             "gif" => Ok(Self::Gif),
             "webp" => Ok(Self::WebP),
-            "tiff" => Ok(Self::Tiff),
+            "tiff" | "tif" => Ok(Self::Tiff),
             _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
         }
     }
 }
 
+impl OutputFormatTypes {
+    pub fn extension(&self, jpeg_extension: JpegExtension) -> &'static str {
+        match self {
+            Self::Jpeg => match jpeg_extension {
+                JpegExtension::Jpg => "jpg",
+                JpegExtension::Jpeg => "jpeg",
+            },
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::WebP => "webp",
+            Self::Tiff => "tiff",
+        }
+    }
+}
+
+impl fmt::Display for OutputFormatTypes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::WebP => "webp",
+            Self::Tiff => "tiff",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl From<OutputFormatTypes> for caesium::SupportedFileTypes {
     fn from(v: OutputFormatTypes) -> Self {
         match v {
@@ -59,6 +88,83 @@ impl From<OutputFormatTypes> for caesium::SupportedFileTypes {
     }
 }
 
+/// Candidate formats of `--target-format auto` when no list is given.
+const AUTO_TARGET_FORMATS: [OutputFormatTypes; 3] = [
+    OutputFormatTypes::WebP,
+    OutputFormatTypes::Jpeg,
+    OutputFormatTypes::Png,
+];
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum TargetFormat {
+    Fixed(OutputFormatTypes),
+    /// Encode into each candidate format and keep the smallest output.
+    Auto(Vec<OutputFormatTypes>),
+}
+
+impl str::FromStr for TargetFormat {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        if lower == "auto" {
+            return Ok(Self::Auto(AUTO_TARGET_FORMATS.to_vec()));
+        }
+
+        let Some(list) = lower.strip_prefix("auto:") else {
+            return Ok(Self::Fixed(s.parse()?));
+        };
+
+        let mut formats: Vec<OutputFormatTypes> = Vec::new();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let format = name.parse()?;
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+
+        if formats.is_empty() {
+            return Err(ValueParseError::new(format!("Invalid value '{}'", s)));
+        }
+
+        Ok(Self::Auto(formats))
+    }
+}
+
+impl fmt::Display for TargetFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fixed(format) => write!(f, "{}", format),
+            Self::Auto(formats) => {
+                let names: Vec<String> = formats.iter().map(|f| f.to_string()).collect();
+                write!(f, "auto ({})", names.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JpegExtension {
+    Jpg,
+    Jpeg,
+}
+
+impl str::FromStr for JpegExtension {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpg" => Ok(Self::Jpg),
+            "jpeg" => Ok(Self::Jpeg),
+            _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ResizeRule {
     NoResize,
@@ -68,6 +174,12 @@ pub enum ResizeRule {
     LongEdge,
     Width,
     Height,
+    /// Contain inside the box of `w` x `h`, keeping the aspect ratio.
+    Fit,
+    /// Fill the box of `w` x `h`, the overflow is cropped by `gravity`.
+    Cover,
+    /// Contain inside the box of `w` x `h`, the rest is filled by `background`.
+    Pad,
 }
 
 impl str::FromStr for ResizeRule {
@@ -82,11 +194,93 @@ impl str::FromStr for ResizeRule {
             "long_edge" => Ok(ResizeRule::LongEdge),
             "width" => Ok(ResizeRule::Width),
             "height" => Ok(ResizeRule::Height),
+            "fit" => Ok(ResizeRule::Fit),
+            "cover" => Ok(ResizeRule::Cover),
+            "pad" => Ok(ResizeRule::Pad),
+            _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
+        }
+    }
+}
+
+/// Where the image is anchored when it's cropped or padded.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+impl str::FromStr for Gravity {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+            "center" | "centre" => Ok(Self::Center),
+            "north" => Ok(Self::North),
+            "south" => Ok(Self::South),
+            "east" => Ok(Self::East),
+            "west" => Ok(Self::West),
+            "northeast" => Ok(Self::NorthEast),
+            "northwest" => Ok(Self::NorthWest),
+            "southeast" => Ok(Self::SouthEast),
+            "southwest" => Ok(Self::SouthWest),
             _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
         }
     }
 }
 
+impl Gravity {
+    /// Offset of the image, or of the kept area, when `free_width` x `free_height` pixels are
+    /// cropped or padded.
+    pub fn offset(self, free_width: u32, free_height: u32) -> (u32, u32) {
+        let (x, y) = match self {
+            Self::Center => (1, 1),
+            Self::North => (1, 0),
+            Self::South => (1, 2),
+            Self::East => (2, 1),
+            Self::West => (0, 1),
+            Self::NorthEast => (2, 0),
+            Self::NorthWest => (0, 0),
+            Self::SouthEast => (2, 2),
+            Self::SouthWest => (0, 2),
+        };
+
+        (free_width * x / 2, free_height * y / 2)
+    }
+}
+
+/// RGBA color, parsed from `#rrggbb`, `#rrggbbaa` (`#` is optional) or `transparent`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Color(pub [u8; 4]);
+
+impl str::FromStr for Color {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("transparent") {
+            return Ok(Self([0, 0, 0, 0]));
+        }
+
+        let hex = s.trim_start_matches('#');
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+            return Err(ValueParseError::new(format!("Invalid value '{}'", s)));
+        }
+
+        let mut rgba = [u8::MAX; 4];
+        for (i, channel) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+
+        Ok(Self(rgba))
+    }
+}
+
 const HELP_TEXT_RESIZE_ARGS: &str = "
 Resize image according to rule and parameters.
 
@@ -94,7 +288,10 @@ Format:
   <rule>:key=value[,key=value...]
 
 Rules:
-  no_resize | size | scale | short_edge | long_edge | width | height
+  no_resize | size | scale | short_edge | long_edge | width | height | fit | cover | pad
+  fit: contain inside the box of w x h, keeping the aspect ratio
+  cover: fill the box of w x h exactly, the overflow is cropped
+  pad: contain inside the box of w x h, and pad it to w x h exactly with `background`
 
 Keys:
   edge_size=<pixels>            Set the shorter or longer side (Depends on the rule) of the original image to this value.
@@ -103,10 +300,17 @@ Keys:
                                 Required when rule is `scale` if width and height is not set.
                                 If `ratio` is set, it takes precedence and ignores the settings of `w` and `h`
   w=<px|0-1>                    Pixel or scale ratio of output image's width.
-                                Required when rule in (size, width) or when rule is `scale` while `ratio` is not set
+                                Required when rule in (size, width, fit, cover, pad) or when rule is `scale` while
+                                `ratio` is not set
   h=<px|0-1>                    Pixel or scale ratio of output image's height.
-                                Required when rule in (size, height) or when rule is `scale` while `ratio` is not set
-  donot_enlarge=<true|false>    Allow enlarging if the origin image size is smaller then given value (default: false)
+                                Required when rule in (size, height, fit, cover, pad) or when rule is `scale` while
+                                `ratio` is not set
+  gravity=<gravity>             Where the image is anchored for `cover` and `pad` (default: center). One of center,
+                                north, south, east, west, north-east, north-west, south-east, south-west
+  background=<color>            Padding color for `pad`, `#rrggbb`, `#rrggbbaa` or `transparent` (default: #ffffff)
+  donot_enlarge=<true|false>    Do not enlarge if the origin image size is smaller then given value, the output size
+                                is scaled down to fit the origin size while keeping aspect ratio, or each edge is
+                                limited to the origin edge when the rule doesn't keep it (default: false)
   keep_aspect_ratio=<bool>      Keep aspect ratio (default: true).
 
 Examples:
@@ -114,6 +318,8 @@ Examples:
   size:width=800,height=600
   scale:ratio=0.8
   scale:w=0.8,h=0.7
+  cover:w=400,h=300,gravity=north
+  pad:w=400,h=300,background=#000000
 ";
 #[derive(Clone, Debug)]
 pub struct ResizeArgs {
@@ -124,9 +330,20 @@ pub struct ResizeArgs {
     pub ratio: f32,
     pub donot_enlarge: bool,
     pub keep_aspect_ratio: bool,
+    pub gravity: Gravity,
+    pub background: Color,
 }
 
 impl ResizeArgs {
+    /// Scale both sides by `ratio`.
+    pub fn scale(ratio: f32) -> Self {
+        Self {
+            rule: ResizeRule::Scale,
+            ratio,
+            ..Self::new()
+        }
+    }
+
     fn new() -> Self {
         Self {
             rule: ResizeRule::NoResize,
@@ -136,6 +353,8 @@ impl ResizeArgs {
             ratio: 0.0,
             donot_enlarge: false,
             keep_aspect_ratio: true,
+            gravity: Gravity::Center,
+            background: Color([u8::MAX; 4]),
         }
     }
 }
@@ -146,7 +365,7 @@ impl str::FromStr for ResizeArgs {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let split = s.splitn(2, ':').collect::<Vec<&str>>();
 
-        let rule = ResizeRule::from_str(split.get(0).unwrap_or(&s))?;
+        let rule = ResizeRule::from_str(split.first().unwrap_or(&s))?;
         let args_map = parse_kv(split.get(1).unwrap_or(&""));
 
         let edge_size_arg = args_map.get("edge_size");
@@ -165,6 +384,14 @@ impl str::FromStr for ResizeArgs {
             resize_args.keep_aspect_ratio = keep_aspect_ratio.parse()?;
         }
 
+        if let Some(gravity) = args_map.get("gravity") {
+            resize_args.gravity = gravity.parse()?;
+        }
+
+        if let Some(background) = args_map.get("background") {
+            resize_args.background = background.parse()?;
+        }
+
         match rule {
             ResizeRule::Size => {
                 if width_arg.is_none() || height_arg.is_none() {
@@ -177,15 +404,15 @@ impl str::FromStr for ResizeArgs {
                 resize_args.height = height_arg.unwrap().parse()?;
             }
             ResizeRule::Scale => {
-                if ratio_arg.is_some() {
-                    resize_args.ratio = ratio_arg.unwrap().parse()?
-                } else if width_arg.is_none() || height_arg.is_none() {
+                if let Some(ratio) = ratio_arg {
+                    resize_args.ratio = ratio.parse()?
+                } else if let (Some(width), Some(height)) = (width_arg, height_arg) {
+                    resize_args.width = width.parse()?;
+                    resize_args.height = height.parse()?;
+                } else {
                     return Err(ValueParseError::from(
                         "width and height is required when resize rule is `scale` and `ratio` is not set.",
                     ));
-                } else {
-                    resize_args.width = width_arg.unwrap().parse()?;
-                    resize_args.height = height_arg.unwrap().parse()?;
                 }
             }
             ResizeRule::ShortEdge | ResizeRule::LongEdge => {
@@ -215,6 +442,21 @@ impl str::FromStr for ResizeArgs {
 
                 resize_args.height = height_arg.unwrap().parse()?;
             }
+            ResizeRule::Fit | ResizeRule::Cover | ResizeRule::Pad => {
+                let (Some(width), Some(height)) = (width_arg, height_arg) else {
+                    return Err(ValueParseError::from(
+                        "w and h are required when resize rule is `fit`, `cover` or `pad`",
+                    ));
+                };
+
+                resize_args.width = width.parse()?;
+                resize_args.height = height.parse()?;
+                if resize_args.width < 1.0 || resize_args.height < 1.0 {
+                    return Err(ValueParseError::from(
+                        "w and h should be pixels when resize rule is `fit`, `cover` or `pad`",
+                    ));
+                }
+            }
             _ => {}
         }
 
@@ -222,6 +464,430 @@ impl str::FromStr for ResizeArgs {
     }
 }
 
+const HELP_TEXT_CROP: &str = "
+Crop the image before resizing, or after it with `--crop-stage after`.
+
+Formats:
+  <x>,<y>,<w>,<h>               Pixel rectangle of the image at the crop stage, e.g. 100,50,800,600
+  <top>%[,<right>%[,<bottom>%[,<left>%]]]
+                                Percentages cut from the edges, like the CSS margin shorthand,
+                                e.g. 10% for all edges, 5%,10% for top/bottom and left/right
+  <w>:<h>[,gravity=<gravity>]   The largest area of the aspect ratio, placed by gravity (default:
+                                center), one of center, north, south, east, west, north-east,
+                                north-west, south-east, south-west
+
+Examples:
+  --crop 0,0,1080,1080
+  --crop 5%,0%
+  --crop 4:5,gravity=north
+  --crop 0,0,1080,1080 --crop-stage after
+";
+#[derive(Clone, PartialEq, Debug)]
+pub enum CropArgs {
+    /// Pixel rectangle of the image at the crop stage.
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Percentages cut from the top, right, bottom and left edges.
+    Insets([f32; 4]),
+    /// The largest area of the aspect ratio `width:height`, placed by gravity.
+    Aspect {
+        width: u32,
+        height: u32,
+        gravity: Gravity,
+    },
+}
+
+impl str::FromStr for CropArgs {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ValueParseError::new(format!("Invalid value '{}'", s));
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+
+        if let Some((width, height)) = parts[0].split_once(':') {
+            let params = parse_kv(s);
+            let (width, height) = (width.parse()?, height.parse()?);
+            if width == 0 || height == 0 {
+                return Err(invalid());
+            }
+
+            return Ok(Self::Aspect {
+                width,
+                height,
+                gravity: params.get("gravity").unwrap_or(&"center").parse()?,
+            });
+        }
+
+        if parts.iter().all(|part| part.ends_with('%')) {
+            let values = parts
+                .iter()
+                .map(|part| part.trim_end_matches('%').parse())
+                .collect::<Result<Vec<f32>, _>>()?;
+            let insets = match values[..] {
+                [all] => [all; 4],
+                [vertical, horizontal] => [vertical, horizontal, vertical, horizontal],
+                [top, horizontal, bottom] => [top, horizontal, bottom, horizontal],
+                [top, right, bottom, left] => [top, right, bottom, left],
+                _ => return Err(invalid()),
+            };
+
+            if insets.iter().any(|inset| !(0.0..100.0).contains(inset))
+                || insets[0] + insets[2] >= 100.0
+                || insets[1] + insets[3] >= 100.0
+            {
+                return Err(ValueParseError::new(format!(
+                    "nothing is left after cutting '{}'",
+                    s
+                )));
+            }
+
+            return Ok(Self::Insets(insets));
+        }
+
+        let [x, y, width, height] = parts[..] else {
+            return Err(invalid());
+        };
+        let (width, height) = (width.parse()?, height.parse()?);
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+
+        Ok(Self::Rect {
+            x: x.parse()?,
+            y: y.parse()?,
+            width,
+            height,
+        })
+    }
+}
+
+/// When `--crop` is applied, relative to resizing.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CropStage {
+    Before,
+    After,
+}
+
+impl str::FromStr for CropStage {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "before" => Ok(Self::Before),
+            "after" => Ok(Self::After),
+            _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ReportFormat {
+    Json,
+    JsonLines,
+    Csv,
+}
+
+impl str::FromStr for ReportFormat {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::JsonLines),
+            "csv" => Ok(Self::Csv),
+            _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
+        }
+    }
+}
+
+/// Size in bytes, parsed from e.g. `204800`, `200KB`, `200KiB`, `1.5MB`.
+/// `KB` / `MB` are multiples of 1000, `KiB` / `MiB` are multiples of 1024.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ByteSize(pub u64);
+
+impl str::FromStr for ByteSize {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let unit_start = trimmed
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(trimmed.len());
+        let (value, unit) = trimmed.split_at(unit_start);
+
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "kib" => 1024,
+            "m" | "mb" => 1000 * 1000,
+            "mib" => 1024 * 1024,
+            "g" | "gb" => 1000 * 1000 * 1000,
+            "gib" => 1024 * 1024 * 1024,
+            _ => return Err(ValueParseError::new(format!("Invalid value '{}'", s))),
+        };
+
+        let value: f64 = value.trim().parse()?;
+        if !value.is_finite() || value <= 0.0 {
+            return Err(ValueParseError::new(format!("Invalid value '{}'", s)));
+        }
+
+        Ok(Self((value * multiplier as f64) as u64))
+    }
+}
+
+// rules
+
+/// Properties of an image checked by the conditions of `--rule`.
+pub struct ImageProperties {
+    pub format: OutputFormatTypes,
+    pub width: usize,
+    pub height: usize,
+    pub file_size: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ImageProperty {
+    Width,
+    Height,
+    Size,
+}
+
+impl str::FromStr for ImageProperty {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "width" => Ok(Self::Width),
+            "height" => Ok(Self::Height),
+            "size" => Ok(Self::Size),
+            _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CompareOperator {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl CompareOperator {
+    /// Operators with their tokens, two-char tokens first so that `<=` is not taken as `<`.
+    const TOKENS: [(&'static str, Self); 5] = [
+        ("<=", Self::LessOrEqual),
+        (">=", Self::GreaterOrEqual),
+        ("<", Self::Less),
+        (">", Self::Greater),
+        ("=", Self::Equal),
+    ];
+
+    fn test(self, actual: u64, expected: u64) -> bool {
+        match self {
+            Self::Less => actual < expected,
+            Self::LessOrEqual => actual <= expected,
+            Self::Equal => actual == expected,
+            Self::GreaterOrEqual => actual >= expected,
+            Self::Greater => actual > expected,
+        }
+    }
+}
+
+/// Condition of a `--rule`, e.g. `png`, `width>2000`, `size<50KB`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum RuleCondition {
+    /// The source format detected from the content.
+    Format(OutputFormatTypes),
+    /// Dimensions in pixels, or the file size in bytes.
+    Compare(ImageProperty, CompareOperator, u64),
+}
+
+impl RuleCondition {
+    pub fn is_match(&self, image: &ImageProperties) -> bool {
+        match self {
+            Self::Format(format) => image.format == *format,
+            Self::Compare(property, operator, expected) => {
+                let actual = match property {
+                    ImageProperty::Width => image.width as u64,
+                    ImageProperty::Height => image.height as u64,
+                    ImageProperty::Size => image.file_size,
+                };
+                operator.test(actual, *expected)
+            }
+        }
+    }
+}
+
+impl str::FromStr for RuleCondition {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(operator_start) = s.find(['<', '>', '=']) else {
+            return Ok(Self::Format(s.trim().parse()?));
+        };
+
+        let (property, rest) = s.split_at(operator_start);
+        let (operator, value) = CompareOperator::TOKENS
+            .iter()
+            .find_map(|(token, operator)| rest.strip_prefix(token).map(|value| (*operator, value)))
+            .ok_or_else(|| ValueParseError::new(format!("Invalid value '{}'", s)))?;
+
+        let property = property.trim().parse()?;
+        let value = match property {
+            ImageProperty::Size => value.parse::<ByteSize>()?.0,
+            ImageProperty::Width | ImageProperty::Height => value.trim().parse()?,
+        };
+
+        Ok(Self::Compare(property, operator, value))
+    }
+}
+
+/// Output of a `--rule`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RuleTarget {
+    /// Convert to the format, overriding `--target-format`.
+    Format(OutputFormatTypes),
+    /// Copy the origin file unchanged.
+    Copy,
+    /// Skip the image, nothing is written.
+    Skip,
+}
+
+/// Keys of `--rule`, other keys are passed to the resize rule.
+const RULE_KEYS: [&str; 4] = ["quality", "lossless", "keep_metadata", "resize"];
+
+const HELP_TEXT_RULE: &str = "
+Settings for images matching conditions, can be given multiple times. All rules matching an image
+are applied in order, a later rule overrides the settings of an earlier one.
+
+Format:
+  <condition>[&<condition>...]=>[<target>][:key=value[,key=value...]]
+
+Conditions:
+  jpg | jpeg | png | gif | webp | tiff | tif   The source format detected from the content
+  width<op><pixels>                            Width of the origin image, e.g. width>2000
+  height<op><pixels>                           Height of the origin image
+  size<op><bytes>                              Size of the origin file, e.g. size<50KB
+  <op> is one of <, <=, =, >=, >
+
+Target:
+  jpg | jpeg | png | gif | webp | tiff | tif   Convert to this format, overrides `--target-format`
+  copy                                         Copy the origin file unchanged, no keys allowed
+  skip                                         Skip the image, no keys allowed
+  <empty>                                      Keep the format decided by other options
+
+Keys:
+  quality=<0-100>               Quality of the output image
+  lossless=<true|false>         Use lossless compression
+  keep_metadata=<true|false>    Keep metadata in the output image
+  resize=<rule>                 Resize rule, takes the keys of `--resize-args` as well, e.g. edge_size
+
+Examples:
+  png=>webp:lossless=true
+  jpg=>jpg:quality=82
+  gif=>copy
+  tiff=>jpg:resize=long_edge,edge_size=2000
+  width>2000=>:resize=width,w=2000
+  png&size>500KB=>webp
+  size<50KB=>skip
+";
+#[derive(Clone, Debug)]
+pub struct ProcessRule {
+    pub conditions: Vec<RuleCondition>,
+    pub target: Option<RuleTarget>,
+    pub quality: Option<u32>,
+    pub lossless: Option<bool>,
+    pub keep_metadata: Option<bool>,
+    pub resize_args: Option<ResizeArgs>,
+}
+
+impl str::FromStr for ProcessRule {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (conditions, action) = s
+            .split_once("=>")
+            .ok_or_else(|| ValueParseError::new(format!("Invalid value '{}'", s)))?;
+        let (target, params) = action.split_once(':').unwrap_or((action, ""));
+
+        let target = match target.trim() {
+            "" => None,
+            "copy" => Some(RuleTarget::Copy),
+            "skip" => Some(RuleTarget::Skip),
+            format => Some(RuleTarget::Format(format.parse()?)),
+        };
+        let params = parse_kv(params);
+
+        if let Some(RuleTarget::Copy | RuleTarget::Skip) = target
+            && !params.is_empty()
+        {
+            return Err(ValueParseError::new(format!(
+                "no keys are allowed when the target is `copy` or `skip`: '{}'",
+                s
+            )));
+        }
+
+        let mut rule = Self {
+            conditions: conditions
+                .split('&')
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            target,
+            quality: None,
+            lossless: None,
+            keep_metadata: None,
+            resize_args: None,
+        };
+
+        if let Some(quality) = params.get("quality") {
+            rule.quality = Some(quality.parse()?);
+        }
+
+        if let Some(lossless) = params.get("lossless") {
+            rule.lossless = Some(lossless.parse()?);
+        }
+
+        if let Some(keep_metadata) = params.get("keep_metadata") {
+            rule.keep_metadata = Some(keep_metadata.parse()?);
+        }
+
+        let resize_params: Vec<String> = params
+            .iter()
+            .filter(|(key, _)| !RULE_KEYS.contains(key))
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        match params.get("resize") {
+            Some(resize) => {
+                rule.resize_args = Some(format!("{}:{}", resize, resize_params.join(",")).parse()?)
+            }
+            None if !resize_params.is_empty() => {
+                return Err(ValueParseError::new(format!(
+                    "`resize` is required for the keys of the resize rule: '{}'",
+                    s
+                )));
+            }
+            None => {}
+        }
+
+        Ok(rule)
+    }
+}
+
+impl ProcessRule {
+    /// Whether the image meets all conditions of the rule.
+    pub fn is_match(&self, image: &ImageProperties) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.is_match(image))
+    }
+}
+
 // jpeg
 
 #[derive(Clone, Debug)]
@@ -524,10 +1190,18 @@ impl From<TiffParams> for cs_params::TiffParameters {
 
 // cli options
 
+const HELP_TEXT_SUBCOMMANDS: &str = "SUBCOMMANDS:
+    compare    Compare two images and print PSNR, SSIM and max difference
+    info       Print format, dimensions, color type and metadata of images
+    presets    List the presets of `--preset` and the options they expand to
+
+Run `imgtool <SUBCOMMAND> --help` for the options of a subcommand.";
+
 #[derive(StructOpt, Clone, Debug)]
 #[structopt(
     name = "imgtool",
-    about = "A simple tool to compress and convert images based on libcaesium."
+    about = "A simple tool to compress and convert images based on libcaesium.",
+    after_help = HELP_TEXT_SUBCOMMANDS
 )]
 pub struct CliOptions {
     /// Input file or directory
@@ -538,6 +1212,18 @@ pub struct CliOptions {
     #[structopt(short, long)]
     pub output: PathBuf,
 
+    /// Config file of default options, if not set, `imgtool.toml` is searched upward from the
+    /// input dir. Options given on the command line override the config file, flags set in it are
+    /// turned off with `--no-<flag>`, e.g. `--no-delete-origin`.
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+
+    /// Apply a preset of options, e.g. `web-thumb`, `print`, `archive-lossless`. The preset
+    /// overrides the config file, options on the command line override the preset, see
+    /// `imgtool presets list`.
+    #[structopt(long)]
+    pub preset: Option<String>,
+
     /// Prefix of output file, if not set, the filename of the output file is same to the origin file.
     #[structopt(short, long)]
     pub prefix: Option<String>,
@@ -546,35 +1232,185 @@ pub struct CliOptions {
     #[structopt(short, long)]
     pub suffix: Option<String>,
 
-    /// Just print the processing plan, no output file
+    /// Process files in subdirectories of the input dir as well, the directory structure is
+    /// recreated under the output dir.
+    #[structopt(short, long, overrides_with = "no-recursive")]
+    pub recursive: bool,
+
+    /// Undo `--recursive`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "recursive")]
+    pub no_recursive: bool,
+
+    /// Max depth of subdirectories to walk when `--recursive` is set, 1 means only the files
+    /// directly in the input dir.
+    #[structopt(long, requires = "recursive")]
+    pub max_depth: Option<usize>,
+
+    /// Follow symbolic links when `--recursive` is set, otherwise symlinks are skipped.
+    #[structopt(long, requires = "recursive", overrides_with = "no-follow-symlinks")]
+    pub follow_symlinks: bool,
+
+    /// Undo `--follow-symlinks`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "follow-symlinks")]
+    pub no_follow_symlinks: bool,
+
+    /// Only process files matching this glob in batch process, can be given multiple times.
+    /// The glob is matched against the path relative to the input dir, e.g. `*.png`, `icons/**`
+    #[structopt(long, number_of_values = 1)]
+    pub include: Vec<Glob>,
+
+    /// Skip files matching this glob in batch process, can be given multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<Glob>,
+
+    /// Only process files with these extensions in batch process (case insensitive), e.g. `png,jpg`.
+    /// Narrows `--include` if both are given, a file must match both.
+    #[structopt(long, use_delimiter = true)]
+    pub ext: Vec<String>,
+
+    /// Number of files to process concurrently, defaults to the number of logical CPUs.
+    #[structopt(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Print the result of each file, including sizes and dimensions.
+    #[structopt(short, long, overrides_with = "no-verbose")]
+    pub verbose: bool,
+
+    /// Undo `--verbose`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "verbose")]
+    pub no_verbose: bool,
+
+    /// Write the result of each file to this path, see `--report-format`.
     #[structopt(long)]
+    pub report: Option<PathBuf>,
+
+    /// Format of the report file.
+    /// Available values: [json, jsonl, csv]
+    #[structopt(long, default_value = "json")]
+    pub report_format: ReportFormat,
+
+    /// Just print the processing plan, no output file
+    #[structopt(long, overrides_with = "no-dry-run")]
     pub dry_run: bool,
 
+    /// Undo `--dry-run`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "dry-run")]
+    pub no_dry_run: bool,
+
     /// Whether to continue with the remaining tasks when an error occurs while processing a file
-    #[structopt(long)]
+    #[structopt(long, overrides_with = "no-continue-on-error")]
     pub continue_on_error: bool,
 
+    /// Undo `--continue-on-error`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "continue-on-error")]
+    pub no_continue_on_error: bool,
+
     /// If the output file size is bigger than the source file, skip (do not output)
-    #[structopt(long)]
+    #[structopt(long, overrides_with = "no-skip-if-bigger")]
     pub skip_if_bigger: bool,
 
+    /// Undo `--skip-if-bigger`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "skip-if-bigger")]
+    pub no_skip_if_bigger: bool,
+
+    /// If the output file size is bigger than the source file, copy the source file to the output instead
+    #[structopt(long, overrides_with = "no-copy-if-bigger")]
+    pub copy_if_bigger: bool,
+
+    /// Undo `--copy-if-bigger`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "copy-if-bigger")]
+    pub no_copy_if_bigger: bool,
+
+    /// Max size of the output file, e.g. `200KB`, `1.5MB`, `204800`. The quality of JPEG, PNG,
+    /// GIF and WebP output is searched from the configured quality downwards until it fits.
+    #[structopt(long)]
+    pub max_size: Option<ByteSize>,
+
+    /// Scale the image down step by step when `--max-size` can't be reached by lowering quality.
+    #[structopt(long, requires = "max-size", overrides_with = "no-shrink-to-fit")]
+    pub shrink_to_fit: bool,
+
+    /// Undo `--shrink-to-fit`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "shrink-to-fit")]
+    pub no_shrink_to_fit: bool,
+
+    /// Min SSIM between the origin and the output, in `(0, 1]`. The lowest quality of JPEG, PNG, GIF
+    /// and WebP output that still reaches it is searched per image, the configured quality is the
+    /// upper bound.
+    #[structopt(long, conflicts_with = "max-size")]
+    pub min_ssim: Option<f64>,
+
     /// Output format, if not set, keep same to the original image.
-    /// Available values: [jpg, jpeg, png, gif, webp, tiff]
+    /// Available values: [jpg, jpeg, png, gif, webp, tiff, tif, auto, auto:<format,...>]
+    /// `auto` encodes each image into every candidate format (default: webp,jpg,png) and keeps the
+    /// smallest output, JPEG and GIF are never picked for images using transparency.
     #[structopt(short, long)]
-    pub target_format: Option<OutputFormatTypes>,
+    pub target_format: Option<TargetFormat>,
+
+    /// Keep the extension of the origin file when converting format, by default the extension of
+    /// output file is changed to match the target format.
+    #[structopt(long, overrides_with = "no-keep-extension")]
+    pub keep_extension: bool,
+
+    /// Undo `--keep-extension`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "keep-extension")]
+    pub no_keep_extension: bool,
+
+    /// Extension used for converted JPEG files.
+    /// Available values: [jpg, jpeg]
+    #[structopt(long, default_value = "jpg")]
+    pub jpeg_extension: JpegExtension,
 
     /// Whether to delete the origin file after process finish.
-    #[structopt(long)]
+    #[structopt(long, overrides_with = "no-delete-origin")]
     pub delete_origin: bool,
 
+    /// Undo `--delete-origin`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "delete-origin")]
+    pub no_delete_origin: bool,
+
     /// Whether to keep metadata in the compressed image
-    #[structopt(long)]
+    #[structopt(long, overrides_with = "no-keep-metadata")]
     pub keep_metadata: bool,
 
+    /// Undo `--keep-metadata`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "keep-metadata")]
+    pub no_keep_metadata: bool,
+
+    /// Rotate the image by its EXIF orientation before cropping and resizing, the output is
+    /// upright without the orientation tag (default). With `--keep-metadata`, the tag is kept and
+    /// the pixels are rotated only if they are changed by `--crop`, `--trim`, `cover` or `pad`.
+    /// Resize rules and `--rule` conditions use the dimensions as displayed.
+    #[structopt(long, overrides_with = "no-auto-orient")]
+    pub auto_orient: bool,
+
+    /// Ignore the EXIF orientation, the pixels are processed and `--rule` conditions are checked
+    /// as stored.
+    #[structopt(long, overrides_with = "auto-orient")]
+    pub no_auto_orient: bool,
+
     /// Whether to use lossless compression (quality may still decline)
-    #[structopt(long)]
+    #[structopt(long, overrides_with = "no-lossless")]
     pub lossless: bool,
 
+    /// Undo `--lossless`, e.g. set in the config file or a preset.
+    #[structopt(long, hidden = true, overrides_with = "lossless")]
+    pub no_lossless: bool,
+
+    #[structopt(long, help = HELP_TEXT_CROP)]
+    pub crop: Option<CropArgs>,
+
+    /// Whether `--crop` is applied to the origin image before resizing, or to the resized image.
+    /// Available values: [before, after]
+    #[structopt(long, default_value = "before")]
+    pub crop_stage: CropStage,
+
+    /// Trim uniform or transparent borders before resizing, after `--crop` unless it's applied
+    /// after resizing. The border color is taken from the top-left pixel, pixels whose channels all
+    /// differ from it by at most the tolerance (0-255, default: 10) belong to the border.
+    #[structopt(long, value_name = "tolerance")]
+    pub trim: Option<Option<u8>>,
+
     #[structopt(long, default_value = "no_resize", help = HELP_TEXT_RESIZE_ARGS)]
     pub resize_args: ResizeArgs,
 
@@ -592,6 +1428,9 @@ pub struct CliOptions {
 
     #[structopt(long, help = HELP_TEXT_TIFF_PARAMS)]
     pub tiff_params: Option<TiffParams>,
+
+    #[structopt(long, number_of_values = 1, help = HELP_TEXT_RULE)]
+    pub rule: Vec<ProcessRule>,
 }
 
 impl From<CliOptions> for cs_params::CSParameters {
@@ -620,3 +1459,289 @@ impl From<CliOptions> for cs_params::CSParameters {
         cs_params
     }
 }
+
+// subcommands
+
+/// Subcommands of imgtool, images are compressed with `CliOptions` when none is given.
+#[derive(StructOpt, Clone, Debug)]
+#[structopt(name = "imgtool")]
+pub enum SubCommand {
+    /// Compare two images and print PSNR, SSIM and max difference
+    Compare(CompareOptions),
+    /// Print format, dimensions, color type and metadata of images
+    Info(InfoOptions),
+    /// List the presets of `--preset` and the options they expand to
+    Presets(PresetsCommand),
+}
+
+impl SubCommand {
+    const NAMES: [&'static str; 3] = ["compare", "info", "presets"];
+
+    /// Whether the command line args (including the program name) start with a subcommand.
+    pub fn is_present(args: &[OsString]) -> bool {
+        args.get(1)
+            .and_then(|arg| arg.to_str())
+            .is_some_and(|arg| Self::NAMES.contains(&arg))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CompareMetric {
+    Ssim,
+    Psnr,
+}
+
+impl str::FromStr for CompareMetric {
+    type Err = ValueParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ssim" => Ok(Self::Ssim),
+            "psnr" => Ok(Self::Psnr),
+            _ => Err(ValueParseError::new(format!("Invalid value '{}'", s))),
+        }
+    }
+}
+
+impl fmt::Display for CompareMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Ssim => "SSIM",
+            Self::Psnr => "PSNR",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct CompareOptions {
+    /// The reference image
+    pub reference: PathBuf,
+
+    /// The image compared with the reference, must have the same dimensions
+    pub candidate: PathBuf,
+
+    /// Write a heatmap of the per-pixel difference to this path, the format is decided by the
+    /// extension. Black means identical, brighter means bigger difference.
+    #[structopt(long)]
+    pub heatmap: Option<PathBuf>,
+
+    /// Exit with a non-zero code if the `--metric` is below this value.
+    #[structopt(long)]
+    pub threshold: Option<f64>,
+
+    /// Metric checked by `--threshold`.
+    /// Available values: [ssim, psnr]
+    #[structopt(long, default_value = "ssim")]
+    pub metric: CompareMetric,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct InfoOptions {
+    /// Image files to inspect
+    #[structopt(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Print the information as a JSON array
+    #[structopt(long)]
+    pub json: bool,
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub enum PresetsCommand {
+    /// List built-in presets and the presets of the config file
+    List(PresetsListOptions),
+}
+
+#[derive(StructOpt, Clone, Debug)]
+pub struct PresetsListOptions {
+    /// Config file with user defined presets, if not set, `imgtool.toml` is searched upward from
+    /// the current dir.
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_size_parses_decimal_and_binary_units() {
+        let cases = [
+            ("1024", 1024),
+            ("300b", 300),
+            ("500k", 500_000),
+            ("500 KB", 500_000),
+            ("1.5KiB", 1536),
+            ("2mb", 2_000_000),
+            ("1MiB", 1024 * 1024),
+            ("0.5g", 500_000_000),
+            ("1GiB", 1024 * 1024 * 1024),
+        ];
+
+        for (s, bytes) in cases {
+            assert_eq!(s.parse::<ByteSize>().unwrap().0, bytes, "'{}'", s);
+        }
+    }
+
+    #[test]
+    fn byte_size_rejects_invalid_values() {
+        for s in ["", "kb", "0", "-1k", "1tb", "1.2.3m", "inf"] {
+            assert!(s.parse::<ByteSize>().is_err(), "'{}'", s);
+        }
+    }
+
+    #[test]
+    fn process_rule_parses_conditions_target_and_keys() {
+        let rule: ProcessRule =
+            "png&size>500KB=>webp:quality=80,lossless=true,resize=long_edge,edge_size=2000"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            rule.conditions,
+            [
+                RuleCondition::Format(OutputFormatTypes::Png),
+                RuleCondition::Compare(ImageProperty::Size, CompareOperator::Greater, 500_000),
+            ]
+        );
+        assert_eq!(
+            rule.target,
+            Some(RuleTarget::Format(OutputFormatTypes::WebP))
+        );
+        assert_eq!(rule.quality, Some(80));
+        assert_eq!(rule.lossless, Some(true));
+        assert_eq!(rule.keep_metadata, None);
+        let resize_args = rule.resize_args.unwrap();
+        assert_eq!(resize_args.rule, ResizeRule::LongEdge);
+        assert_eq!(resize_args.edge_size, 2000);
+    }
+
+    #[test]
+    fn process_rule_parses_empty_copy_and_skip_targets() {
+        let rule: ProcessRule = "width>=2000=>:keep_metadata=true".parse().unwrap();
+        assert_eq!(
+            rule.conditions,
+            [RuleCondition::Compare(
+                ImageProperty::Width,
+                CompareOperator::GreaterOrEqual,
+                2000
+            )]
+        );
+        assert_eq!(rule.target, None);
+        assert_eq!(rule.keep_metadata, Some(true));
+
+        let rule: ProcessRule = "gif=>copy".parse().unwrap();
+        assert_eq!(rule.target, Some(RuleTarget::Copy));
+        let rule: ProcessRule = "height<=10=>skip".parse().unwrap();
+        assert_eq!(rule.target, Some(RuleTarget::Skip));
+    }
+
+    #[test]
+    fn process_rule_rejects_invalid_values() {
+        for s in [
+            "png",
+            "bmp=>webp",
+            "png=>bmp",
+            "depth>1=>webp",
+            "width>wide=>webp",
+            "gif=>copy:quality=80",
+            "size<1KB=>skip:lossless=true",
+            "png=>webp:edge_size=2000",
+            "png=>webp:quality=high",
+        ] {
+            assert!(s.parse::<ProcessRule>().is_err(), "'{}'", s);
+        }
+    }
+
+    #[test]
+    fn process_rule_matches_all_conditions() {
+        let rule: ProcessRule = "png&width>100&size<50KB=>skip".parse().unwrap();
+        let image = ImageProperties {
+            format: OutputFormatTypes::Png,
+            width: 200,
+            height: 100,
+            file_size: 10_000,
+        };
+
+        assert!(rule.is_match(&image));
+        assert!(!rule.is_match(&ImageProperties {
+            format: OutputFormatTypes::Jpeg,
+            ..image
+        }));
+        assert!(!rule.is_match(&ImageProperties {
+            width: 100,
+            ..image
+        }));
+        assert!(!rule.is_match(&ImageProperties {
+            file_size: 50_000,
+            ..image
+        }));
+    }
+
+    #[test]
+    fn crop_args_parses_rect_insets_and_aspect() {
+        assert_eq!(
+            "100, 50, 800, 600".parse::<CropArgs>().unwrap(),
+            CropArgs::Rect {
+                x: 100,
+                y: 50,
+                width: 800,
+                height: 600
+            }
+        );
+        assert_eq!(
+            "10%".parse::<CropArgs>().unwrap(),
+            CropArgs::Insets([10.0; 4])
+        );
+        assert_eq!(
+            "5%,10%".parse::<CropArgs>().unwrap(),
+            CropArgs::Insets([5.0, 10.0, 5.0, 10.0])
+        );
+        assert_eq!(
+            "1%,2%,3%".parse::<CropArgs>().unwrap(),
+            CropArgs::Insets([1.0, 2.0, 3.0, 2.0])
+        );
+        assert_eq!(
+            "1%,2%,3%,4%".parse::<CropArgs>().unwrap(),
+            CropArgs::Insets([1.0, 2.0, 3.0, 4.0])
+        );
+        assert_eq!(
+            "4:5".parse::<CropArgs>().unwrap(),
+            CropArgs::Aspect {
+                width: 4,
+                height: 5,
+                gravity: Gravity::Center
+            }
+        );
+        assert_eq!(
+            "16:9,gravity=north-west".parse::<CropArgs>().unwrap(),
+            CropArgs::Aspect {
+                width: 16,
+                height: 9,
+                gravity: Gravity::NorthWest
+            }
+        );
+    }
+
+    #[test]
+    fn crop_args_rejects_invalid_values() {
+        for s in [
+            "",
+            "100,50,800",
+            "0,0,0,600",
+            "0,0,800,-1",
+            "10%,20",
+            "1%,2%,3%,4%,5%",
+            "50%",
+            "60%,0%",
+            "0%,30%,0%,70%",
+            "-1%",
+            "0:5",
+            "4:five",
+            "4:5,gravity=up",
+        ] {
+            assert!(s.parse::<CropArgs>().is_err(), "'{}'", s);
+        }
+    }
+}
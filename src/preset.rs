@@ -0,0 +1,179 @@
+use crate::config::{self, Config, OptionArgs};
+use crate::error::ImageProcessError;
+use crate::options::PresetsListOptions;
+use std::env;
+use toml::Value;
+
+/// Name, description and `(option, value)` pairs of a preset, an empty value is a flag.
+type PresetDefinition = (
+    &'static str,
+    &'static str,
+    &'static [(&'static str, &'static str)],
+);
+
+const BUILTIN_PRESETS: [PresetDefinition; 4] = [
+    (
+        "web",
+        "WebP for web pages, long edge at most 1920px, metadata stripped",
+        &[
+            ("target-format", "webp"),
+            ("webp-params", "quality=80"),
+            ("resize-args", "long_edge:edge_size=1920,donot_enlarge=true"),
+        ],
+    ),
+    (
+        "web-thumb",
+        "Small WebP thumbnail, long edge at most 400px, metadata stripped",
+        &[
+            ("target-format", "webp"),
+            ("webp-params", "quality=70"),
+            ("resize-args", "long_edge:edge_size=400,donot_enlarge=true"),
+        ],
+    ),
+    (
+        "print",
+        "High quality JPEG in the origin size, metadata kept",
+        &[
+            ("target-format", "jpg"),
+            (
+                "jpeg-params",
+                "quality=95,chroma_subsampling=cs444,progressive=false",
+            ),
+            ("keep-metadata", ""),
+        ],
+    ),
+    (
+        "archive-lossless",
+        "Lossless compression in the origin format, metadata kept",
+        &[
+            ("lossless", ""),
+            ("keep-metadata", ""),
+            ("png-params", "optimization_level=6"),
+            ("tiff-params", "algorithm=deflate,deflate_level=best"),
+        ],
+    ),
+];
+
+/// A named bundle of options, selected with `--preset` or the `preset` key of the config file.
+#[derive(Clone, Debug)]
+pub struct Preset {
+    pub name: String,
+    pub description: String,
+    pub builtin: bool,
+    pub options: Vec<OptionArgs>,
+}
+
+impl Preset {
+    /// The options of the preset as command line args.
+    pub fn args(&self) -> Vec<String> {
+        self.options
+            .iter()
+            .flat_map(|(_, args)| args.iter().cloned())
+            .collect()
+    }
+}
+
+pub fn builtin_presets() -> Vec<Preset> {
+    BUILTIN_PRESETS
+        .iter()
+        .map(|(name, description, options)| Preset {
+            name: name.to_string(),
+            description: description.to_string(),
+            builtin: true,
+            options: options
+                .iter()
+                .map(|(option, value)| {
+                    let mut args = vec![format!("--{}", option)];
+                    if !value.is_empty() {
+                        args.push(value.to_string());
+                    }
+                    (option.to_string(), args)
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Parse the `[presets.<name>]` tables of the config file, the keys of a preset are the same as
+/// the config file, plus an optional `description`.
+pub fn user_presets(value: &Value) -> Result<Vec<Preset>, String> {
+    let Value::Table(table) = value else {
+        return Err("`presets` should be tables of `[presets.<name>]`".to_string());
+    };
+
+    let mut presets = Vec::new();
+    for (name, value) in table {
+        let Value::Table(entries) = value else {
+            return Err(format!("preset `{}` should be a table", name));
+        };
+
+        let mut preset = Preset {
+            name: name.clone(),
+            description: String::new(),
+            builtin: false,
+            options: Vec::new(),
+        };
+
+        for (key, value) in entries {
+            let option = key.replace('_', "-");
+            match (option.as_str(), value) {
+                ("description", Value::String(description)) => {
+                    preset.description = description.clone()
+                }
+                ("config" | "input" | "output" | "preset", _) => {
+                    return Err(format!("`{}` can't be set in preset `{}`", option, name));
+                }
+                _ => preset
+                    .options
+                    .push((option.clone(), config::to_args(&option, value)?)),
+            }
+        }
+
+        presets.push(preset);
+    }
+
+    Ok(presets)
+}
+
+/// Find a preset by name, user defined presets take precedence over built-in ones.
+pub fn find(name: &str, user_presets: &[Preset]) -> Option<Preset> {
+    user_presets
+        .iter()
+        .find(|preset| preset.name == name)
+        .cloned()
+        .or_else(|| {
+            builtin_presets()
+                .into_iter()
+                .find(|preset| preset.name == name)
+        })
+}
+
+/// Print all presets and the options they expand to for `presets list`.
+pub fn list(options: &PresetsListOptions) -> Result<(), ImageProcessError> {
+    let current_dir = env::current_dir()?;
+    let config = match Config::locate(options.config.as_deref(), Some(&current_dir)) {
+        Some(path) => Some(Config::load(&path)?),
+        None => None,
+    };
+
+    let mut presets = builtin_presets();
+    if let Some(config) = &config {
+        presets.extend(config.presets.iter().cloned());
+    }
+
+    for preset in &presets {
+        let source = match &config {
+            Some(config) if !preset.builtin => config.path.display().to_string(),
+            _ => "built-in".to_string(),
+        };
+
+        if preset.description.is_empty() {
+            println!("{} ({})", preset.name, source);
+        } else {
+            println!("{} ({}): {}", preset.name, source, preset.description);
+        }
+        println!("    {}", preset.args().join(" "));
+    }
+
+    Ok(())
+}
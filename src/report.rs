@@ -0,0 +1,397 @@
+use crate::error::ImageProcessError;
+use crate::options::{OutputFormatTypes, ReportFormat};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Count of the slowest files listed in the summary.
+const SLOWEST_FILES_COUNT: usize = 5;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FileStatus {
+    Ok,
+    Skipped,
+    Failed,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Ok => "ok",
+            Self::Skipped => "skipped",
+            Self::Failed => "failed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Result of processing one file.
+#[derive(Clone, Debug)]
+pub struct FileReport {
+    pub input_file: PathBuf,
+    pub output_file: PathBuf,
+    pub status: FileStatus,
+    /// Reason of skipping, error message of failure, or a note of a successful process.
+    pub message: Option<String>,
+    pub source_format: Option<OutputFormatTypes>,
+    pub target_format: Option<OutputFormatTypes>,
+    pub origin_dimensions: Option<(usize, usize)>,
+    pub output_dimensions: Option<(usize, usize)>,
+    /// Quality chosen by the `--max-size` or `--min-ssim` search.
+    pub quality: Option<u32>,
+    /// SSIM between the origin and the output, computed with `--min-ssim`.
+    pub ssim: Option<f64>,
+    /// Pixels cut by `--trim` from the top, right, bottom and left edges.
+    pub trimmed: Option<[u32; 4]>,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub duration: Duration,
+}
+
+impl FileReport {
+    pub fn new(input_file: PathBuf, output_file: PathBuf) -> Self {
+        Self {
+            input_file,
+            output_file,
+            status: FileStatus::Ok,
+            message: None,
+            source_format: None,
+            target_format: None,
+            origin_dimensions: None,
+            output_dimensions: None,
+            quality: None,
+            ssim: None,
+            trimmed: None,
+            input_bytes: 0,
+            output_bytes: 0,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub fn skip(&mut self, reason: String) {
+        self.status = FileStatus::Skipped;
+        self.message = Some(reason);
+    }
+
+    pub fn fail(&mut self, err: String) {
+        self.status = FileStatus::Failed;
+        self.message = Some(err);
+    }
+
+    /// One line description of the file, used by `--verbose`.
+    pub fn describe(&self) -> String {
+        let mut line = format!("[{}] {}", self.status, self.input_file.display());
+
+        if self.status == FileStatus::Ok {
+            line += &format!(
+                " -> {}, {} -> {} ({:.1}%)",
+                self.output_file.display(),
+                format_bytes(self.input_bytes),
+                format_bytes(self.output_bytes),
+                ratio_percent(self.output_bytes, self.input_bytes)
+            );
+
+            if let (Some(origin), Some(output)) = (self.origin_dimensions, self.output_dimensions) {
+                line += &format!(", {}x{} -> {}x{}", origin.0, origin.1, output.0, output.1);
+            }
+
+            if let Some(quality) = self.quality {
+                line += &format!(", quality {}", quality);
+            }
+
+            if let Some(ssim) = self.ssim {
+                line += &format!(", ssim {:.4}", ssim);
+            }
+
+            if let Some([top, right, bottom, left]) = self.trimmed {
+                line += &format!(
+                    ", trimmed top {}, right {}, bottom {}, left {}",
+                    top, right, bottom, left
+                );
+            }
+        }
+
+        if let Some(message) = &self.message {
+            line += &format!(": {}", message);
+        }
+
+        line
+    }
+}
+
+/// Flat record of a `FileReport`, written to the `--report` file.
+#[derive(Serialize)]
+struct ReportRecord {
+    input: String,
+    output: String,
+    status: String,
+    message: Option<String>,
+    source_format: Option<String>,
+    target_format: Option<String>,
+    origin_width: Option<usize>,
+    origin_height: Option<usize>,
+    output_width: Option<usize>,
+    output_height: Option<usize>,
+    quality: Option<u32>,
+    ssim: Option<f64>,
+    trim_top: Option<u32>,
+    trim_right: Option<u32>,
+    trim_bottom: Option<u32>,
+    trim_left: Option<u32>,
+    input_bytes: u64,
+    output_bytes: u64,
+    duration_ms: f64,
+}
+
+impl From<&FileReport> for ReportRecord {
+    fn from(report: &FileReport) -> Self {
+        Self {
+            input: report.input_file.to_string_lossy().to_string(),
+            output: report.output_file.to_string_lossy().to_string(),
+            status: report.status.to_string(),
+            message: report.message.clone(),
+            source_format: report.source_format.map(|f| f.to_string()),
+            target_format: report.target_format.map(|f| f.to_string()),
+            origin_width: report.origin_dimensions.map(|d| d.0),
+            origin_height: report.origin_dimensions.map(|d| d.1),
+            output_width: report.output_dimensions.map(|d| d.0),
+            output_height: report.output_dimensions.map(|d| d.1),
+            quality: report.quality,
+            ssim: report.ssim,
+            trim_top: report.trimmed.map(|t| t[0]),
+            trim_right: report.trimmed.map(|t| t[1]),
+            trim_bottom: report.trimmed.map(|t| t[2]),
+            trim_left: report.trimmed.map(|t| t[3]),
+            input_bytes: report.input_bytes,
+            output_bytes: report.output_bytes,
+            duration_ms: report.duration.as_micros() as f64 / 1000.0,
+        }
+    }
+}
+
+/// Write reports of all files to `path` in the given format.
+pub fn write_report(
+    reports: &[FileReport],
+    path: &Path,
+    format: ReportFormat,
+) -> Result<(), ImageProcessError> {
+    let records: Vec<ReportRecord> = reports.iter().map(ReportRecord::from).collect();
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    match format {
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &records).map_err(io::Error::from)?;
+            writeln!(writer)?;
+        }
+        ReportFormat::JsonLines => {
+            for record in &records {
+                serde_json::to_writer(&mut writer, record).map_err(io::Error::from)?;
+                writeln!(writer)?;
+            }
+        }
+        ReportFormat::Csv => {
+            let mut csv_writer = csv::Writer::from_writer(&mut writer);
+            for record in &records {
+                csv_writer.serialize(record).map_err(io::Error::from)?;
+            }
+            csv_writer.flush()?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Summary of a batch run, printed after all files are processed.
+pub struct Summary<'a> {
+    reports: &'a [FileReport],
+    filtered_files: usize,
+}
+
+impl<'a> Summary<'a> {
+    pub fn new(reports: &'a [FileReport], filtered_files: usize) -> Self {
+        Self {
+            reports,
+            filtered_files,
+        }
+    }
+
+    fn count(&self, status: FileStatus) -> usize {
+        self.reports.iter().filter(|r| r.status == status).count()
+    }
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Processed: {}, skipped: {}, failed: {}",
+            self.count(FileStatus::Ok),
+            self.count(FileStatus::Skipped),
+            self.count(FileStatus::Failed)
+        )?;
+        if self.filtered_files > 0 {
+            write!(f, ", filtered out: {}", self.filtered_files)?;
+        }
+        writeln!(f)?;
+
+        let processed = self.reports.iter().filter(|r| r.status == FileStatus::Ok);
+        let (input_bytes, output_bytes) = processed.fold((0, 0), |(input, output), r| {
+            (input + r.input_bytes, output + r.output_bytes)
+        });
+        write!(
+            f,
+            "Total: {} -> {}, saved {:.1}%",
+            format_bytes(input_bytes),
+            format_bytes(output_bytes),
+            100.0 - ratio_percent(output_bytes, input_bytes)
+        )?;
+
+        let mut slowest: Vec<&FileReport> = self
+            .reports
+            .iter()
+            .filter(|r| r.status != FileStatus::Skipped)
+            .collect();
+        if slowest.len() > 1 {
+            slowest.sort_by_key(|r| Reverse(r.duration));
+            write!(f, "\nSlowest:")?;
+            for report in slowest.iter().take(SLOWEST_FILES_COUNT) {
+                write!(
+                    f,
+                    "\n  {:>8.2}s  {}",
+                    report.duration.as_secs_f64(),
+                    report.input_file.display()
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn ratio_percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sample_reports() -> Vec<FileReport> {
+        let mut converted = FileReport::new(PathBuf::from("in/a.png"), PathBuf::from("out/a.webp"));
+        converted.source_format = Some(OutputFormatTypes::Png);
+        converted.target_format = Some(OutputFormatTypes::WebP);
+        converted.origin_dimensions = Some((800, 600));
+        converted.output_dimensions = Some((400, 300));
+        converted.quality = Some(72);
+        converted.trimmed = Some([0, 10, 0, 10]);
+        converted.input_bytes = 2048;
+        converted.output_bytes = 512;
+        converted.duration = Duration::from_micros(1500);
+
+        let mut skipped = FileReport::new(PathBuf::from("in/b.txt"), PathBuf::from("out/b.txt"));
+        skipped.skip("not an image".to_string());
+
+        vec![converted, skipped]
+    }
+
+    fn written_report(format: ReportFormat, name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("imgtool-report-{}-{}", std::process::id(), name));
+        write_report(&sample_reports(), &path, format).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        content
+    }
+
+    #[test]
+    fn json_report_is_an_array_of_records() {
+        let content = written_report(ReportFormat::Json, "report.json");
+        let records: serde_json::Value = serde_json::from_str(&content).unwrap();
+        let records = records.as_array().unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0],
+            serde_json::json!({
+                "input": "in/a.png",
+                "output": "out/a.webp",
+                "status": "ok",
+                "message": null,
+                "source_format": "png",
+                "target_format": "webp",
+                "origin_width": 800,
+                "origin_height": 600,
+                "output_width": 400,
+                "output_height": 300,
+                "quality": 72,
+                "ssim": null,
+                "trim_top": 0,
+                "trim_right": 10,
+                "trim_bottom": 0,
+                "trim_left": 10,
+                "input_bytes": 2048,
+                "output_bytes": 512,
+                "duration_ms": 1.5,
+            })
+        );
+        assert_eq!(records[1]["status"], "skipped");
+        assert_eq!(records[1]["message"], "not an image");
+        assert_eq!(records[1]["source_format"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn jsonl_report_has_a_record_per_line() {
+        let content = written_report(ReportFormat::JsonLines, "report.jsonl");
+        let lines: Vec<&str> = content.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        let record: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(record["input"], "in/b.txt");
+        assert_eq!(record["status"], "skipped");
+    }
+
+    #[test]
+    fn csv_report_has_a_header_and_empty_cells_for_missing_values() {
+        let content = written_report(ReportFormat::Csv, "report.csv");
+        let lines: Vec<&str> = content.lines().collect();
+
+        assert_eq!(
+            lines,
+            [
+                "input,output,status,message,source_format,target_format,origin_width,\
+                 origin_height,output_width,output_height,quality,ssim,trim_top,trim_right,trim_bottom,\
+                 trim_left,input_bytes,output_bytes,duration_ms",
+                "in/a.png,out/a.webp,ok,,png,webp,800,600,400,300,72,,0,10,0,10,2048,512,1.5",
+                "in/b.txt,out/b.txt,skipped,not an image,,,,,,,,,,,,,0,0,0.0",
+            ]
+        );
+    }
+}
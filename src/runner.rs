@@ -1,27 +1,159 @@
-use crate::error::ImageProcessError;
-use crate::options::{CliOptions, ResizeArgs, ResizeRule};
+use crate::error::{CaesiumDiagnostic, ImageProcessError, ValueParseError};
+use crate::filter::InputFilter;
+use crate::metrics::{self, LumaPlane};
+use crate::options::{
+    CliOptions, CropArgs, CropStage, ImageProperties, OutputFormatTypes, ResizeArgs, ResizeRule,
+    RuleTarget, TargetFormat,
+};
+use crate::report::{self, FileReport, FileStatus, Summary};
+use crate::transform;
+use caesium::error::CaesiumError;
 use caesium::parameters::CSParameters;
-use caesium::SupportedFileTypes;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::WebPDecoder;
+use image::error::UnsupportedError;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, ImageDecoder, ImageEncoder, ImageReader,
+};
+use imagesize::{ImageSize, ImageType};
+use std::collections::HashMap;
 use std::fs;
+use std::io::{self, Cursor, Read};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use walkdir::WalkDir;
 
 pub struct RunConfiguration {
-    target_format: Option<SupportedFileTypes>,
+    output_extension: Option<String>,
+    input_filter: InputFilter,
     caesium_parameters: CSParameters,
 
     options: CliOptions,
 }
 
-impl From<CliOptions> for RunConfiguration {
-    fn from(options: CliOptions) -> Self {
-        Self {
-            target_format: options.target_format.map_or(None, |v| Some(v.into())),
+impl TryFrom<CliOptions> for RunConfiguration {
+    type Error = ValueParseError;
+
+    fn try_from(options: CliOptions) -> Result<Self, Self::Error> {
+        if let Some(min_ssim) = options.min_ssim
+            && !(min_ssim > 0.0 && min_ssim <= 1.0)
+        {
+            return Err(ValueParseError::new(format!(
+                "--min-ssim should be in (0, 1], but given {}",
+                min_ssim
+            )));
+        }
+
+        Ok(Self {
+            output_extension: match &options.target_format {
+                Some(TargetFormat::Fixed(format)) if !options.keep_extension => {
+                    Some(format.extension(options.jpeg_extension).to_string())
+                }
+                // The extension of `auto` is decided after encoding.
+                _ => None,
+            },
+            input_filter: InputFilter::try_from(&options)?,
             caesium_parameters: options.clone().into(),
             options,
+        })
+    }
+}
+
+impl RunConfiguration {
+    /// Settings of an image after applying the `--rule`s matching it.
+    fn file_settings(&self, image: &ImageProperties) -> FileSettings<'_> {
+        let options = &self.options;
+        let mut settings = FileSettings {
+            caesium_parameters: self.caesium_parameters,
+            resize_args: &options.resize_args,
+            target_format: options.target_format.clone(),
+            copy: false,
+            skip: false,
+        };
+
+        for rule in options.rule.iter().filter(|rule| rule.is_match(image)) {
+            match rule.target {
+                Some(RuleTarget::Format(format)) => {
+                    settings.target_format = Some(TargetFormat::Fixed(format));
+                    (settings.copy, settings.skip) = (false, false);
+                }
+                Some(RuleTarget::Copy) => (settings.copy, settings.skip) = (true, false),
+                Some(RuleTarget::Skip) => (settings.copy, settings.skip) = (false, true),
+                None => {}
+            }
+
+            let caesium_parameters = &mut settings.caesium_parameters;
+            if let Some(quality) = rule.quality {
+                caesium_parameters.jpeg.quality = quality;
+                caesium_parameters.png.quality = quality;
+                caesium_parameters.gif.quality = quality;
+                caesium_parameters.webp.quality = quality;
+            }
+            if let Some(lossless) = rule.lossless {
+                caesium_parameters.optimize = lossless;
+            }
+            if let Some(keep_metadata) = rule.keep_metadata {
+                caesium_parameters.keep_metadata = keep_metadata;
+            }
+            if let Some(resize_args) = &rule.resize_args {
+                settings.resize_args = resize_args;
+            }
         }
+
+        settings
     }
 }
 
+/// Settings of one image, see `RunConfiguration::file_settings`.
+struct FileSettings<'a> {
+    caesium_parameters: CSParameters,
+    resize_args: &'a ResizeArgs,
+    target_format: Option<TargetFormat>,
+    /// Copy the origin file unchanged.
+    copy: bool,
+    /// Skip the image.
+    skip: bool,
+}
+
+impl FileSettings<'_> {
+    /// Format named by the extension of the output file, the format picked by `auto` is only
+    /// known after encoding.
+    fn output_format(&self, source_format: OutputFormatTypes) -> OutputFormatTypes {
+        match &self.target_format {
+            Some(TargetFormat::Fixed(format)) if !self.copy => *format,
+            _ => source_format,
+        }
+    }
+}
+
+/// Bytes to read for sniffing the image format.
+const HEADER_SIZE: usize = 256;
+
+/// Percentage of the size kept in each step of `--shrink-to-fit`.
+const SHRINK_STEP_PERCENT: usize = 90;
+/// Stop shrinking when the shorter side would be smaller than this.
+const SHRINK_MIN_EDGE: usize = 16;
+
+/// Output of one candidate format.
+struct EncodedImage {
+    data: Vec<u8>,
+    format: OutputFormatTypes,
+    quality: Option<u32>,
+    ssim: Option<f64>,
+}
+
+enum ContentFormat {
+    Supported(OutputFormatTypes),
+    Unsupported(ImageType),
+    NotImage,
+}
+
 pub struct Runner {
     run_configuration: RunConfiguration,
 }
@@ -37,12 +169,30 @@ impl Runner {
         let run_configuration = &self.run_configuration;
         let options = &self.run_configuration.options;
 
+        let (tasks, filtered_files) = self.make_tasks()?;
+
+        if options.dry_run {
+            return Self::print_plan(&tasks, run_configuration);
+        }
+
+        let (reports, result) = Self::run_tasks(&tasks, run_configuration);
+        println!("{}", Summary::new(&reports, filtered_files));
+
+        if let Some(report_path) = &options.report {
+            report::write_report(&reports, report_path, options.report_format)?;
+        }
+
+        result
+    }
+
+    /// Make `(input_file, output_file)` tasks, also returns the count of files filtered out.
+    fn make_tasks(&self) -> Result<(Vec<(PathBuf, PathBuf)>, usize), ImageProcessError> {
+        let run_configuration = &self.run_configuration;
+        let options = &self.run_configuration.options;
+
         let input = &options.input;
         if !input.exists() {
-            return Err(ImageProcessError::new(format!(
-                "File or dir not exists: {}",
-                input.to_string_lossy()
-            )));
+            return Err(ImageProcessError::InputNotFound(input.clone()));
         }
 
         let output = options.output.clone();
@@ -50,55 +200,562 @@ impl Runner {
         // Just run once for file input.
         if input.is_file() {
             let output_file = if output.is_dir() {
-                Self::make_path(&input, &output, &options.prefix, &options.suffix)
+                let image = Self::read_properties(input, options);
+                Self::make_output_path(input, image.as_ref(), &output, run_configuration)
             } else {
                 output
             };
 
-            return Self::run_process(&input, &output_file, run_configuration);
+            return Ok((vec![(input.clone(), output_file)], 0));
         }
 
         // Batch process
 
         // If input is dir, the output should be also a dir
         if !output.exists() {
-            fs::create_dir_all(&output).map_err(|e| ImageProcessError::new(e.to_string()))?
+            if !options.dry_run {
+                fs::create_dir_all(&output)?
+            }
         } else if output.is_file() {
-            return Err(ImageProcessError::new(format!(
+            return Err(ImageProcessError::InvalidArguments(format!(
                 "When input is a dir, output should also be a dir, but given a file: {}",
                 output.to_str().unwrap_or("")
             )));
         }
 
-        let input_files: Vec<PathBuf> = fs::read_dir(&input)?
-            .filter_map(|e| e.ok())
-            .map(|dir_entry| dir_entry.path())
-            .filter_map(|path| if path.is_file() { Some(path) } else { None })
-            .collect();
-
         let output_dir = output;
+        let mut input_files = Self::collect_input_files(input, &output_dir, options)?;
+        // The config file placed in the input dir is not an input file.
+        if let Some(config_file) = options
+            .config
+            .as_ref()
+            .and_then(|path| path.canonicalize().ok())
+        {
+            input_files.retain(|input_file| {
+                input_file.file_name() != config_file.file_name()
+                    || input_file.canonicalize().ok().as_ref() != Some(&config_file)
+            });
+        }
+
+        let mut tasks: Vec<(PathBuf, PathBuf)> = Vec::new();
+        // Whether the input file of each task is a supported image not skipped by `--rule`, so its
+        // output is written.
+        let mut written = Vec::new();
+        for input_file in input_files.iter().filter(|input_file| {
+            let relative_path = input_file.strip_prefix(input).unwrap_or(input_file);
+            run_configuration.input_filter.is_match(relative_path)
+        }) {
+            // Mirror the relative directory of the input file under the output dir.
+            let relative_dir = input_file
+                .parent()
+                .and_then(|parent| parent.strip_prefix(input).ok())
+                .unwrap_or(Path::new(""));
+            let image = Self::read_properties(input_file, options);
+            let output_file = Self::make_output_path(
+                input_file,
+                image.as_ref(),
+                &output_dir.join(relative_dir),
+                run_configuration,
+            );
+            written.push(image.is_some_and(|image| !run_configuration.file_settings(&image).skip));
+            tasks.push((input_file.clone(), output_file));
+        }
+        Self::resolve_output_collisions(&mut tasks, &written, &input_files, options)?;
+
+        let filtered_files = input_files.len() - tasks.len();
+
+        Ok((tasks, filtered_files))
+    }
+
+    /// Rewriting the extension may map an input to the output of another input or to another file
+    /// of the input dir, e.g. `a.png` and `a.jpg` to `a.webp`. The colliding outputs with a
+    /// rewritten extension keep the source extension before the new one, e.g. `a.png.webp`.
+    ///
+    /// Only the outputs of the tasks marked in `written` are checked, other input files are skipped
+    /// and never written.
+    fn resolve_output_collisions(
+        tasks: &mut [(PathBuf, PathBuf)],
+        written: &[bool],
+        input_files: &[PathBuf],
+        options: &CliOptions,
+    ) -> Result<(), ImageProcessError> {
+        // The extension picked by `auto` is only known after encoding, so outputs differing only
+        // in the extension may collide too.
+        let auto_extension =
+            !options.keep_extension && matches!(options.target_format, Some(TargetFormat::Auto(_)));
+        let output_key = |path: &Path| {
+            if auto_extension {
+                path.with_extension("")
+            } else {
+                path.to_path_buf()
+            }
+        };
+
+        let mut input_counts: HashMap<PathBuf, usize> = HashMap::new();
         for input_file in input_files {
-            let output_file =
-                Self::make_path(&input_file, &output_dir, &options.prefix, &options.suffix);
+            *input_counts.entry(output_key(input_file)).or_default() += 1;
+        }
+        let collided_tasks = |tasks: &[(PathBuf, PathBuf)]| -> Vec<usize> {
+            let written_tasks = || {
+                tasks
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| written[*index])
+            };
 
-            let result = Self::run_process(&input_file, &output_file, run_configuration);
-            if let Err(err) = result {
-                if !run_configuration.options.continue_on_error {
-                    return Err(err);
-                } else {
-                    eprintln!("ImageProcessError of file '{:?}': {}", input_file, err);
+            let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+            for (_, (_, output_file)) in written_tasks() {
+                *counts.entry(output_key(output_file)).or_default() += 1;
+            }
+            // An output overwriting another input file collides too, except writing in place.
+            written_tasks()
+                .filter(|(_, (input_file, output_file))| {
+                    let key = output_key(output_file);
+                    let in_place = output_key(input_file) == key;
+                    counts[&key] > 1
+                        || input_counts.get(&key).copied().unwrap_or(0) > usize::from(in_place)
+                })
+                .map(|(index, _)| index)
+                .collect()
+        };
+
+        for index in collided_tasks(tasks) {
+            let (input_file, output_file) = &mut tasks[index];
+            let source_extension = input_file.extension().unwrap_or_default();
+            let source_named = output_file.with_extension(source_extension);
+            if source_named == *output_file && !auto_extension {
+                continue;
+            }
+
+            if let Some(extension) = output_file.extension() {
+                let mut filename = source_named.file_name().unwrap_or_default().to_os_string();
+                filename.push(".");
+                filename.push(extension);
+                *output_file = output_file.with_file_name(filename);
+            }
+        }
+
+        match collided_tasks(tasks).first() {
+            Some(&index) => {
+                let (input_file, output_file) = &tasks[index];
+                Err(ImageProcessError::InvalidArguments(format!(
+                    "Output file {} of {} is also another input file or its output",
+                    output_file.display(),
+                    input_file.display()
+                )))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Process tasks with a pool of worker threads, each worker takes the next pending task until
+    /// all tasks are done or an error occurs without `continue_on_error`.
+    ///
+    /// Reports are returned in the order of tasks, along with the error that stopped processing.
+    fn run_tasks(
+        tasks: &[(PathBuf, PathBuf)],
+        run_configuration: &RunConfiguration,
+    ) -> (Vec<FileReport>, Result<(), ImageProcessError>) {
+        let options = &run_configuration.options;
+
+        let jobs = match options.jobs {
+            Some(jobs) if jobs > 0 => jobs,
+            _ => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let jobs = jobs.min(tasks.len()).max(1);
+
+        let next_task = AtomicUsize::new(0);
+        let first_error: Mutex<Option<ImageProcessError>> = Mutex::new(None);
+        // The first error ignored because of `continue_on_error`.
+        let continued_error: Mutex<Option<ImageProcessError>> = Mutex::new(None);
+        let reports: Mutex<Vec<(usize, FileReport)>> = Mutex::new(Vec::with_capacity(tasks.len()));
+
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| {
+                    loop {
+                        if first_error.lock().unwrap().is_some() {
+                            break;
+                        }
+
+                        let index = next_task.fetch_add(1, Ordering::SeqCst);
+                        let Some((input_file, output_file)) = tasks.get(index) else {
+                            break;
+                        };
+
+                        let mut report = FileReport::new(input_file.clone(), output_file.clone());
+                        let start = Instant::now();
+                        let result = Self::run_process(
+                            input_file,
+                            output_file,
+                            run_configuration,
+                            &mut report,
+                        );
+                        report.duration = start.elapsed();
+
+                        if let Err(err) = result {
+                            report.fail(err.to_string());
+                            if !options.continue_on_error {
+                                first_error.lock().unwrap().get_or_insert(err);
+                            } else {
+                                if !options.verbose {
+                                    eprintln!(
+                                        "ImageProcessError of file '{:?}': {}",
+                                        input_file, err
+                                    );
+                                }
+                                continued_error.lock().unwrap().get_or_insert(err);
+                            }
+                        }
+
+                        if options.verbose {
+                            println!("{}", report.describe());
+                        } else if report.status == FileStatus::Skipped {
+                            println!(
+                                "Skipped '{}': {}",
+                                input_file.display(),
+                                report.message.as_deref().unwrap_or("")
+                            );
+                        } else if let Some(message) = &report.message
+                            && report.status == FileStatus::Ok
+                        {
+                            println!("'{}': {}", input_file.display(), message);
+                        }
+
+                        reports.lock().unwrap().push((index, report));
+                    }
+                });
+            }
+        });
+
+        let mut reports = reports.into_inner().unwrap();
+        reports.sort_by_key(|(index, _)| *index);
+        let reports: Vec<FileReport> = reports.into_iter().map(|(_, report)| report).collect();
+
+        if let Some(err) = first_error.into_inner().unwrap() {
+            return (reports, Err(err));
+        }
+
+        let failed = reports
+            .iter()
+            .filter(|report| report.status == FileStatus::Failed)
+            .count();
+        let result = match continued_error.into_inner().unwrap() {
+            // Single file, report the error itself.
+            Some(err) if tasks.len() == 1 => Err(err),
+            Some(_) => Err(ImageProcessError::PartialFailure {
+                failed,
+                total: tasks.len(),
+            }),
+            None => Ok(()),
+        };
+
+        (reports, result)
+    }
+
+    fn collect_input_files(
+        input_dir: &Path,
+        output_dir: &Path,
+        options: &CliOptions,
+    ) -> Result<Vec<PathBuf>, ImageProcessError> {
+        if !options.recursive {
+            return Ok(fs::read_dir(input_dir)?
+                .filter_map(|e| e.ok())
+                .map(|dir_entry| dir_entry.path())
+                .filter_map(|path| if path.is_file() { Some(path) } else { None })
+                .collect());
+        }
+
+        let walker = WalkDir::new(input_dir)
+            .min_depth(1)
+            .max_depth(options.max_depth.unwrap_or(usize::MAX))
+            .follow_links(options.follow_symlinks)
+            .sort_by_file_name();
+
+        let mut input_files = Vec::new();
+        // Do not walk into the output dir if it's placed inside the input dir.
+        for entry in walker
+            .into_iter()
+            .filter_entry(|entry| output_dir == input_dir || entry.path() != output_dir)
+        {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => input_files.push(entry.into_path()),
+                Ok(_) => {}
+                Err(err) => {
+                    let err = ImageProcessError::from(io::Error::from(err));
+                    if !options.continue_on_error {
+                        return Err(err);
+                    }
+                    eprintln!("ImageProcessError while walking '{:?}': {}", input_dir, err);
                 }
             }
         }
 
+        Ok(input_files)
+    }
+
+    /// Print what would be done for each task without writing or deleting anything.
+    fn print_plan(
+        tasks: &[(PathBuf, PathBuf)],
+        run_configuration: &RunConfiguration,
+    ) -> Result<(), ImageProcessError> {
+        let options = &run_configuration.options;
+
+        for (input_file, output_file) in tasks {
+            // Files which are not supported images are never written, so no output is listed.
+            let source_format = match Self::detect_format(&Self::read_header(input_file)) {
+                ContentFormat::Supported(format) => format,
+                ContentFormat::Unsupported(image_type) => {
+                    println!(
+                        "{}: skip (unsupported image format {:?})",
+                        input_file.display(),
+                        image_type
+                    );
+                    continue;
+                }
+                ContentFormat::NotImage => {
+                    println!("{}: skip (not an image)", input_file.display());
+                    continue;
+                }
+            };
+
+            println!("{} -> {}", input_file.display(), output_file.display());
+
+            let image_size = match imagesize::size(input_file) {
+                Ok(image_size) => image_size,
+                Err(err) => {
+                    let err = ImageProcessError::from(err);
+                    if !options.continue_on_error {
+                        return Err(err);
+                    }
+                    println!("    size: unknown ({})", err);
+                    continue;
+                }
+            };
+            let orientation = Self::read_file_orientation(input_file, options);
+            let image_size = transform::oriented_size(image_size, orientation);
+
+            let settings = run_configuration.file_settings(&ImageProperties {
+                format: source_format,
+                width: image_size.width,
+                height: image_size.height,
+                file_size: fs::metadata(input_file)?.len(),
+            });
+            if settings.skip {
+                println!("    skipped by rule");
+                continue;
+            }
+            if settings.copy {
+                println!("    copied unchanged by rule");
+                if options.delete_origin {
+                    println!("    origin file will be deleted");
+                }
+                continue;
+            }
+
+            println!(
+                "    format: {}",
+                settings
+                    .target_format
+                    .as_ref()
+                    .map_or("same as source".to_string(), |format| format.to_string())
+            );
+
+            let mut image_size = image_size;
+            if let Some(crop) = &options.crop
+                && options.crop_stage == CropStage::Before
+            {
+                match Self::plan_crop(crop, image_size, "crop", options)? {
+                    Some(cropped_size) => image_size = cropped_size,
+                    None => continue,
+                }
+            }
+
+            if let Some(orientation) = orientation {
+                println!("    auto orient: {:?}", orientation);
+            }
+
+            if let Some(tolerance) = options.trim {
+                println!(
+                    "    trim: tolerance {}",
+                    tolerance.unwrap_or(transform::DEFAULT_TRIM_TOLERANCE)
+                );
+            }
+
+            let mut caesium_parameters = settings.caesium_parameters;
+            Self::set_scaled_size(&mut caesium_parameters, settings.resize_args, image_size);
+            println!(
+                "    size: {}x{} -> {}",
+                image_size.width,
+                image_size.height,
+                Self::describe_size(&caesium_parameters)
+            );
+
+            if let Some(crop) = &options.crop
+                && options.crop_stage == CropStage::After
+            {
+                let (width, height) = Self::scaled_size(&caesium_parameters, image_size);
+                let resized_size = ImageSize { width, height };
+                if Self::plan_crop(crop, resized_size, "crop after resizing", options)?.is_none() {
+                    continue;
+                }
+            }
+
+            if let Some(max_size) = options.max_size {
+                println!("    max size: {} bytes", max_size.0);
+            }
+
+            if let Some(min_ssim) = options.min_ssim {
+                println!("    min ssim: {}", min_ssim);
+            }
+
+            if options.delete_origin {
+                println!("    origin file will be deleted");
+            }
+        }
+
+        println!("Dry run: {} file(s) planned, nothing written.", tasks.len());
+
         Ok(())
     }
 
+    /// Print the `--crop` area of the image for the plan, returns the size of the area, or `None` if
+    /// it's outside of the image and `continue_on_error` is set.
+    fn plan_crop(
+        crop: &CropArgs,
+        image_size: ImageSize,
+        label: &str,
+        options: &CliOptions,
+    ) -> Result<Option<ImageSize>, ImageProcessError> {
+        let dimensions = (image_size.width as u32, image_size.height as u32);
+        match transform::crop_area(crop, dimensions) {
+            Ok((x, y, width, height)) => {
+                println!("    {}: {}x{} at {},{}", label, width, height, x, y);
+                Ok(Some(ImageSize {
+                    width: width as usize,
+                    height: height as usize,
+                }))
+            }
+            Err(err) if options.continue_on_error => {
+                println!("    {}: {}", label, err);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn describe_size(caesium_parameters: &CSParameters) -> String {
+        let edge = |v: u32| {
+            if v == 0 {
+                "auto".to_string()
+            } else {
+                v.to_string()
+            }
+        };
+
+        if caesium_parameters.width == 0 && caesium_parameters.height == 0 {
+            "no resize".to_string()
+        } else {
+            format!(
+                "{}x{}",
+                edge(caesium_parameters.width),
+                edge(caesium_parameters.height)
+            )
+        }
+    }
+
+    /// Make the output path in `output_dir` for the input file.
+    ///
+    /// The extension is taken from the target format, or from the real format sniffed from the
+    /// content when the input file is mislabeled, e.g. a JPEG named `.png`.
+    fn make_output_path(
+        input_file: &Path,
+        image: Option<&ImageProperties>,
+        output_dir: &Path,
+        run_configuration: &RunConfiguration,
+    ) -> PathBuf {
+        let options = &run_configuration.options;
+
+        let extension = match &run_configuration.output_extension {
+            Some(extension) if options.rule.is_empty() => Some(extension.clone()),
+            _ if !options.keep_extension => match image {
+                Some(image) => Some(
+                    run_configuration
+                        .file_settings(image)
+                        .output_format(image.format)
+                        .extension(options.jpeg_extension)
+                        .to_string(),
+                ),
+                None => run_configuration.output_extension.clone(),
+            },
+            _ => None,
+        };
+
+        Self::make_path(
+            input_file,
+            output_dir,
+            &options.prefix,
+            &options.suffix,
+            extension.as_deref(),
+        )
+    }
+
+    fn read_header(input_file: &Path) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        if let Ok(file) = fs::File::open(input_file) {
+            let _ = file.take(HEADER_SIZE as u64).read_to_end(&mut header);
+        }
+        header
+    }
+
+    /// Properties of a supported image for the conditions of `--rule`, `None` if it's not one.
+    fn read_properties(input_file: &Path, options: &CliOptions) -> Option<ImageProperties> {
+        let ContentFormat::Supported(format) = Self::detect_format(&Self::read_header(input_file))
+        else {
+            return None;
+        };
+        let image_size = transform::oriented_size(
+            imagesize::size(input_file).ok()?,
+            Self::read_file_orientation(input_file, options),
+        );
+
+        Some(ImageProperties {
+            format,
+            width: image_size.width,
+            height: image_size.height,
+            file_size: fs::metadata(input_file).ok()?.len(),
+        })
+    }
+
+    /// EXIF orientation applied by `--auto-orient`, `None` if it's disabled or the image is upright.
+    fn read_file_orientation(input_file: &Path, options: &CliOptions) -> Option<Orientation> {
+        if options.no_auto_orient {
+            return None;
+        }
+
+        let file = fs::File::open(input_file).ok()?;
+        transform::read_orientation(io::BufReader::new(file))
+    }
+
+    /// Detect the real format of the image from the magic bytes.
+    fn detect_format(header: &[u8]) -> ContentFormat {
+        match imagesize::image_type(header) {
+            Ok(ImageType::Jpeg) => ContentFormat::Supported(OutputFormatTypes::Jpeg),
+            Ok(ImageType::Png) => ContentFormat::Supported(OutputFormatTypes::Png),
+            Ok(ImageType::Gif) => ContentFormat::Supported(OutputFormatTypes::Gif),
+            Ok(ImageType::Webp) => ContentFormat::Supported(OutputFormatTypes::WebP),
+            Ok(ImageType::Tiff) => ContentFormat::Supported(OutputFormatTypes::Tiff),
+            Ok(image_type) => ContentFormat::Unsupported(image_type),
+            Err(_) => ContentFormat::NotImage,
+        }
+    }
+
     fn make_path(
-        input_file: &PathBuf,
-        output_dir: &PathBuf,
+        input_file: &Path,
+        output_dir: &Path,
         prefix: &Option<String>,
         suffix: &Option<String>,
+        extension: Option<&str>,
     ) -> PathBuf {
         input_file
             .file_name()
@@ -118,11 +775,27 @@ impl Runner {
                 }
             })
             .map(|filename| output_dir.join(filename))
+            .map(|path| match extension {
+                Some(ext) if !Self::is_same_format_extension(&path, ext) => {
+                    path.with_extension(ext)
+                }
+                _ => path,
+            })
             .unwrap()
     }
 
-    fn get_parted_filename(filename: &String) -> (String, Option<String>) {
-        if filename == "" {
+    /// Whether the extension of the path already names the same format as `ext`, e.g. `jpeg` and `JPG`.
+    fn is_same_format_extension(path: &Path, ext: &str) -> bool {
+        let origin_format = path
+            .extension()
+            .and_then(|origin_ext| origin_ext.to_str())
+            .and_then(|origin_ext| origin_ext.parse::<OutputFormatTypes>().ok());
+
+        origin_format.is_some() && origin_format == ext.parse().ok()
+    }
+
+    fn get_parted_filename(filename: &str) -> (String, Option<String>) {
+        if filename.is_empty() {
             return ("".to_string(), None);
         }
 
@@ -139,59 +812,702 @@ impl Runner {
     }
 
     fn run_process(
-        input_file: &PathBuf,
-        output_file: &PathBuf,
+        input_file: &Path,
+        output_file: &Path,
         run_configuration: &RunConfiguration,
+        report: &mut FileReport,
     ) -> Result<(), ImageProcessError> {
         let options = &run_configuration.options;
 
-        let origin_data = fs::read(input_file).map_err(|e| ImageProcessError::from(e))?;
+        let origin_data = fs::read(input_file).map_err(ImageProcessError::from)?;
+        let origin_len = origin_data.len();
+        report.input_bytes = origin_len as u64;
+
+        let source_format = match Self::detect_format(&origin_data) {
+            ContentFormat::Supported(format) => format,
+            ContentFormat::Unsupported(image_type) => {
+                report.skip(format!("unsupported image format ({:?})", image_type));
+                return Ok(());
+            }
+            ContentFormat::NotImage => {
+                report.skip("not an image".to_string());
+                return Ok(());
+            }
+        };
+        report.source_format = Some(source_format);
 
-        let mut caesium_parameters = run_configuration.caesium_parameters.clone();
+        let stored_size = imagesize::blob_size(&origin_data)?;
+        let orientation = transform::read_orientation(Cursor::new(&origin_data));
+        // Rules use the dimensions as displayed, unless the orientation is ignored.
+        let image_size = if options.no_auto_orient {
+            stored_size
+        } else {
+            transform::oriented_size(stored_size, orientation)
+        };
+        report.origin_dimensions = Some((image_size.width, image_size.height));
 
-        let resize_args = &options.resize_args;
-        if resize_args.rule != ResizeRule::NoResize {
-            Self::set_scaled_size(&mut caesium_parameters, resize_args, &origin_data)?;
+        let settings = run_configuration.file_settings(&ImageProperties {
+            format: source_format,
+            width: image_size.width,
+            height: image_size.height,
+            file_size: origin_len as u64,
+        });
+        if settings.skip {
+            report.skip("skipped by rule".to_string());
+            return Ok(());
+        }
+        if settings.copy {
+            report.message = Some("copied unchanged by rule".to_string());
+            return Self::copy_origin(input_file, output_file, options, report);
         }
 
-        let compressed = match &run_configuration.target_format {
-            None => caesium::compress_in_memory(origin_data, &caesium_parameters),
-            Some(format) => {
-                // origin_data.clone()...?
-                let convert_result = caesium::convert_in_memory(
-                    origin_data.clone(),
-                    &caesium_parameters,
-                    format.clone(),
-                );
+        let mut caesium_parameters = settings.caesium_parameters;
 
-                // code == 10407: output format same to the origin, just compress it
-                if let Err(err) = &convert_result
-                    && err.code == 10407
-                {
-                    caesium::compress_in_memory(origin_data, &caesium_parameters)
-                } else {
-                    convert_result
+        let resize_args = settings.resize_args;
+
+        // libcaesium only resizes, the pixels are changed here first if needed.
+        let transformed = Self::transform(
+            &origin_data,
+            source_format,
+            options,
+            resize_args,
+            caesium_parameters.keep_metadata,
+            orientation.filter(|_| !options.no_auto_orient),
+            report,
+        )?;
+        let (origin_data, data_format, image_size) = match &transformed {
+            Some(data) => (
+                data.as_slice(),
+                OutputFormatTypes::Png,
+                imagesize::blob_size(data)?,
+            ),
+            // libcaesium resizes JPEG by the dimensions as displayed, other formats as stored.
+            None if source_format == OutputFormatTypes::Jpeg => (
+                origin_data.as_slice(),
+                source_format,
+                transform::oriented_size(stored_size, orientation),
+            ),
+            None => (origin_data.as_slice(), source_format, stored_size),
+        };
+
+        // Cropping after resizing resizes the image in `transform` already.
+        let resized = options.crop.is_some() && options.crop_stage == CropStage::After;
+        if resize_args.rule != ResizeRule::NoResize && !resized {
+            Self::set_scaled_size(&mut caesium_parameters, resize_args, image_size);
+        }
+
+        let candidates = match &settings.target_format {
+            None => vec![source_format],
+            Some(TargetFormat::Fixed(format)) => vec![*format],
+            Some(TargetFormat::Auto(formats)) => Self::auto_candidates(formats, origin_data)?,
+        };
+
+        // Keep the smallest output, errors of other candidates are ignored in `auto` mode.
+        let mut encoded: Option<EncodedImage> = None;
+        let mut last_error = None;
+        for format in candidates {
+            match Self::encode(
+                origin_data,
+                caesium_parameters,
+                run_configuration,
+                data_format,
+                format,
+                image_size,
+            ) {
+                Ok(candidate) => {
+                    if encoded
+                        .as_ref()
+                        .is_none_or(|best| candidate.data.len() < best.data.len())
+                    {
+                        encoded = Some(candidate);
+                    }
                 }
+                Err(err) => last_error = Some(err),
             }
+        }
+
+        let encoded = match (encoded, last_error) {
+            (Some(encoded), _) => encoded,
+            (None, Some(err)) => return Err(err),
+            (None, None) => unreachable!("at least one candidate format"),
         };
 
-        let compressed = compressed?;
+        report.target_format = Some(encoded.format);
+        report.quality = encoded.quality;
+        report.ssim = encoded.ssim;
+        if let (Some(ssim), Some(min_ssim)) = (encoded.ssim, options.min_ssim)
+            && ssim < min_ssim
+        {
+            report.message = Some(format!(
+                "ssim {:.4} is below {} even at the configured quality",
+                ssim, min_ssim
+            ));
+        }
+
+        let compressed = encoded.data;
+
+        if (options.skip_if_bigger || options.copy_if_bigger) && compressed.len() > origin_len {
+            let reason = format!(
+                "output ({} bytes) is bigger than origin ({} bytes)",
+                compressed.len(),
+                origin_len
+            );
+
+            if !options.copy_if_bigger {
+                report.skip(reason);
+                return Ok(());
+            }
+
+            report.message = Some(format!("copied origin, {}", reason));
+            report.output_file = Self::origin_output_path(input_file, output_file, options);
+            let output_file = report.output_file.clone();
+            return Self::copy_origin(input_file, &output_file, options, report);
+        }
 
+        report.output_bytes = compressed.len() as u64;
+        // Like the origin, the output with the orientation tag kept is measured as displayed.
+        let output_orientation = transform::read_orientation(Cursor::new(&compressed))
+            .filter(|_| !options.no_auto_orient);
+        report.output_dimensions = imagesize::blob_size(&compressed)
+            .ok()
+            .map(|size| transform::oriented_size(size, output_orientation))
+            .map(|size| (size.width, size.height));
+
+        // The extension follows the format picked by `auto`.
+        if let Some(TargetFormat::Auto(_)) = &settings.target_format
+            && !options.keep_extension
+        {
+            let extension = encoded.format.extension(options.jpeg_extension);
+            if !Self::is_same_format_extension(output_file, extension) {
+                report.output_file = output_file.with_extension(extension);
+            }
+        }
+        let output_file = report.output_file.as_path();
+
+        Self::create_parent_dir(output_file)?;
+
+        if options.delete_origin {
+            fs::remove_file(input_file).map_err(ImageProcessError::from)?;
+        }
+
+        fs::write(output_file, &compressed).map_err(ImageProcessError::from)
+    }
+
+    /// The output path to copy the origin file to, an extension rewritten for the target format is
+    /// changed back to the source one, e.g. `a.webp` or `a.png.webp` to `a.png`. The output file
+    /// named by `--output` is kept as is.
+    fn origin_output_path(input_file: &Path, output_file: &Path, options: &CliOptions) -> PathBuf {
+        if output_file == options.output {
+            return output_file.to_path_buf();
+        }
+
+        let source_extension = input_file.extension();
+        // The source extension is already kept before the new one on output collisions.
+        let renamed = output_file.with_extension("");
+        if renamed.extension() == source_extension {
+            return renamed;
+        }
+
+        output_file.with_extension(source_extension.unwrap_or_default())
+    }
+
+    /// Copy the origin file to the output unchanged.
+    fn copy_origin(
+        input_file: &Path,
+        output_file: &Path,
+        options: &CliOptions,
+        report: &mut FileReport,
+    ) -> Result<(), ImageProcessError> {
+        report.target_format = report.source_format;
+        report.output_bytes = report.input_bytes;
+        report.output_dimensions = report.origin_dimensions;
+
+        // Output to the origin file itself, nothing to do.
+        if input_file == output_file {
+            return Ok(());
+        }
+
+        Self::create_parent_dir(output_file)?;
+        fs::copy(input_file, output_file).map_err(ImageProcessError::from)?;
         if options.delete_origin {
-            fs::remove_file(&input_file).map_err(|e| ImageProcessError::from(e))?;
+            fs::remove_file(input_file).map_err(ImageProcessError::from)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply the pixel operations libcaesium lacks: the EXIF orientation, `--crop`, `--trim`, then
+    /// crop or pad the image to the aspect ratio of the box for the `cover` and `pad` resize rules,
+    /// libcaesium resizes it to the box afterwards. With `--crop-stage after`, `--trim` is applied
+    /// first, the image is resized here and cropped last instead. The result is encoded as lossless
+    /// PNG, `None` if the image is left as is.
+    ///
+    /// With `keep_metadata`, the ICC profile and EXIF of the origin image are carried to the PNG for
+    /// libcaesium to keep, the orientation tag is reset if the pixels are rotated. Animated images
+    /// can't be transformed, only the first frame is decoded.
+    fn transform(
+        origin_data: &[u8],
+        source_format: OutputFormatTypes,
+        options: &CliOptions,
+        resize_args: &ResizeArgs,
+        keep_metadata: bool,
+        orientation: Option<Orientation>,
+        report: &mut FileReport,
+    ) -> Result<Option<Vec<u8>>, ImageProcessError> {
+        let fits_box = matches!(resize_args.rule, ResizeRule::Cover | ResizeRule::Pad);
+        let changes_pixels = options.crop.is_some() || options.trim.is_some() || fits_box;
+
+        // Metadata is lost when re-encoding, if it's kept, the orientation tag keeps the image
+        // displayed upright unless the pixels are changed anyway.
+        let orientation = orientation.filter(|_| changes_pixels || !keep_metadata);
+        if !changes_pixels && orientation.is_none() {
+            return Ok(None);
+        }
+
+        if Self::is_animated(origin_data, source_format)? {
+            // Only the orientation to apply, the frames are left as stored.
+            if !changes_pixels {
+                return Ok(None);
+            }
+            return Err(ImageProcessError::InvalidArguments(
+                "--crop, --trim, `cover` and `pad` can't be applied to animated images".to_string(),
+            ));
+        }
+        // The image crate doesn't read the EXIF of TIFF images.
+        if keep_metadata && source_format == OutputFormatTypes::Tiff {
+            return Err(ImageProcessError::InvalidArguments(
+                "the metadata of TIFF images can't be kept with --crop, --trim, `cover` or `pad`"
+                    .to_string(),
+            ));
+        }
+
+        let mut decoder = ImageReader::new(Cursor::new(origin_data))
+            .with_guessed_format()?
+            .into_decoder()?;
+        let (icc_profile, mut exif) = if keep_metadata {
+            (decoder.icc_profile()?, decoder.exif_metadata()?)
+        } else {
+            (None, None)
+        };
+        let mut image = DynamicImage::from_decoder(decoder)?;
+
+        if let Some(orientation) = orientation {
+            image.apply_orientation(orientation);
+            if let Some(exif) = &mut exif {
+                let _ = Orientation::remove_from_exif_chunk(exif);
+            }
+        }
+
+        let (crop_before, crop_after) = match (&options.crop, options.crop_stage) {
+            (Some(crop), CropStage::Before) => (Some(crop), None),
+            (Some(crop), CropStage::After) => (None, Some(crop)),
+            (None, _) => (None, None),
+        };
+
+        if let Some(crop) = crop_before {
+            let (x, y, width, height) = transform::crop_area(crop, image.dimensions())?;
+            image = image.crop_imm(x, y, width, height);
+        }
+
+        if let Some(tolerance) = options.trim {
+            let tolerance = tolerance.unwrap_or(transform::DEFAULT_TRIM_TOLERANCE);
+            if let Some(trimmed @ [top, right, bottom, left]) =
+                transform::trim_borders(&image, tolerance)
+            {
+                let width = image.width() - left - right;
+                let height = image.height() - top - bottom;
+                image = image.crop_imm(left, top, width, height);
+                report.trimmed = Some(trimmed);
+            }
+        }
+
+        let (width, height) = (resize_args.width as u32, resize_args.height as u32);
+        image = match resize_args.rule {
+            ResizeRule::Cover => {
+                transform::crop_to_aspect(&image, width, height, resize_args.gravity)
+            }
+            ResizeRule::Pad => transform::pad_to_aspect(
+                &image,
+                width,
+                height,
+                resize_args.gravity,
+                resize_args.background,
+            ),
+            _ => image,
+        };
+
+        if let Some(crop) = crop_after {
+            // Resize like libcaesium does, the output is not resized again.
+            let image_size = ImageSize {
+                width: image.width() as usize,
+                height: image.height() as usize,
+            };
+            let mut caesium_parameters = CSParameters::new();
+            Self::set_scaled_size(&mut caesium_parameters, resize_args, image_size);
+            let (width, height) = Self::scaled_size(&caesium_parameters, image_size);
+            if (width, height) != (image_size.width, image_size.height) {
+                image = image.resize_exact(width as u32, height as u32, FilterType::Lanczos3);
+            }
+
+            let (x, y, width, height) = transform::crop_area(crop, image.dimensions())?;
+            image = image.crop_imm(x, y, width, height);
+        }
+
+        let metadata_error = |err: UnsupportedError| {
+            ImageProcessError::InvalidArguments(format!("can't keep the metadata: {}", err))
+        };
+        let mut data = Vec::new();
+        let mut encoder = PngEncoder::new(&mut data);
+        if let Some(icc_profile) = icc_profile {
+            encoder
+                .set_icc_profile(icc_profile)
+                .map_err(metadata_error)?;
+        }
+        if let Some(exif) = exif {
+            encoder.set_exif_metadata(exif).map_err(metadata_error)?;
+        }
+        image.write_with_encoder(encoder)?;
+        Ok(Some(data))
+    }
+
+    /// Whether the image has more than one frame.
+    fn is_animated(
+        origin_data: &[u8],
+        source_format: OutputFormatTypes,
+    ) -> Result<bool, ImageProcessError> {
+        let reader = Cursor::new(origin_data);
+        Ok(match source_format {
+            OutputFormatTypes::Gif => GifDecoder::new(reader)?.into_frames().nth(1).is_some(),
+            OutputFormatTypes::Png => PngDecoder::new(reader)?.is_apng()?,
+            OutputFormatTypes::WebP => WebPDecoder::new(reader)?.has_animation(),
+            OutputFormatTypes::Jpeg | OutputFormatTypes::Tiff => false,
+        })
+    }
+
+    /// Formats tried by `--target-format auto`, formats without full transparency support are
+    /// dropped if the image uses transparency.
+    fn auto_candidates(
+        formats: &[OutputFormatTypes],
+        origin_data: &[u8],
+    ) -> Result<Vec<OutputFormatTypes>, ImageProcessError> {
+        let keeps_alpha = |format: &OutputFormatTypes| {
+            !matches!(format, OutputFormatTypes::Jpeg | OutputFormatTypes::Gif)
+        };
+        if formats.iter().all(keeps_alpha) {
+            return Ok(formats.to_vec());
+        }
+
+        let image = image::load_from_memory(origin_data)?;
+        let uses_alpha = image.color().has_alpha()
+            && image.to_rgba8().pixels().any(|pixel| pixel.0[3] < u8::MAX);
+        if !uses_alpha {
+            return Ok(formats.to_vec());
+        }
+
+        let candidates: Vec<OutputFormatTypes> =
+            formats.iter().copied().filter(keeps_alpha).collect();
+        if candidates.is_empty() {
+            return Err(ImageProcessError::Encode(
+                "the image uses transparency, but no candidate format of `auto` supports it"
+                    .to_string(),
+            ));
+        }
+
+        Ok(candidates)
+    }
+
+    /// Encode the image into `format`, searching the quality for `--max-size` or `--min-ssim`.
+    fn encode(
+        origin_data: &[u8],
+        caesium_parameters: CSParameters,
+        run_configuration: &RunConfiguration,
+        source_format: OutputFormatTypes,
+        format: OutputFormatTypes,
+        image_size: ImageSize,
+    ) -> Result<EncodedImage, ImageProcessError> {
+        let options = &run_configuration.options;
+        let mut encoded = EncodedImage {
+            data: Vec::new(),
+            format,
+            quality: None,
+            ssim: None,
+        };
+
+        if let Some(max_size) = options.max_size {
+            (encoded.data, encoded.quality) = Self::compress_to_size(
+                origin_data,
+                caesium_parameters,
+                run_configuration,
+                source_format,
+                format,
+                image_size,
+                max_size.0 as usize,
+            )?;
+        } else if let Some(min_ssim) = options.min_ssim {
+            let ssim;
+            (encoded.data, encoded.quality, ssim) = Self::compress_to_ssim(
+                origin_data,
+                caesium_parameters,
+                source_format,
+                format,
+                min_ssim,
+            )?;
+            encoded.ssim = Some(ssim);
+        } else {
+            encoded.data = Self::compress(origin_data, &caesium_parameters, source_format, format)?;
+        }
+
+        Ok(encoded)
+    }
+
+    fn compress(
+        origin_data: &[u8],
+        caesium_parameters: &CSParameters,
+        source_format: OutputFormatTypes,
+        format: OutputFormatTypes,
+    ) -> Result<Vec<u8>, CaesiumError> {
+        if format == source_format {
+            return caesium::compress_in_memory(origin_data.to_vec(), caesium_parameters);
+        }
+
+        let convert_result =
+            caesium::convert_in_memory(origin_data.to_vec(), caesium_parameters, format.into());
+
+        // output format same to the origin, just compress it
+        if let Err(err) = &convert_result
+            && CaesiumDiagnostic::from(err).can_fallback_to_compress()
+        {
+            caesium::compress_in_memory(origin_data.to_vec(), caesium_parameters)
+        } else {
+            convert_result
+        }
+    }
+
+    /// Compress the image to fit `max_size` bytes, scale it down step by step if `--shrink-to-fit`
+    /// is set and lowering quality is not enough. Also returns the quality finally chosen.
+    fn compress_to_size(
+        origin_data: &[u8],
+        mut caesium_parameters: CSParameters,
+        run_configuration: &RunConfiguration,
+        source_format: OutputFormatTypes,
+        format: OutputFormatTypes,
+        image_size: ImageSize,
+        max_size: usize,
+    ) -> Result<(Vec<u8>, Option<u32>), ImageProcessError> {
+        if image_size.width == 0 || image_size.height == 0 {
+            return Err(ImageProcessError::Decode(format!(
+                "invalid image size {}x{}",
+                image_size.width, image_size.height
+            )));
+        }
+
+        loop {
+            let (compressed, quality) = Self::search_quality(
+                origin_data,
+                &mut caesium_parameters,
+                source_format,
+                format,
+                max_size,
+            )?;
+
+            if compressed.len() <= max_size {
+                return Ok((compressed, quality));
+            }
+
+            let (width, height) = Self::scaled_size(&caesium_parameters, image_size);
+            if !run_configuration.options.shrink_to_fit {
+                return Err(ImageProcessError::Encode(format!(
+                    "can't compress to {} bytes, the smallest output is {} bytes, raise \
+                     `--max-size` or add `--shrink-to-fit`",
+                    max_size,
+                    compressed.len()
+                )));
+            }
+            if width.min(height) * SHRINK_STEP_PERCENT / 100 < SHRINK_MIN_EDGE {
+                return Err(ImageProcessError::Encode(format!(
+                    "can't compress to {} bytes even shrunk to {}x{}, the smallest output is {} \
+                     bytes, raise `--max-size`",
+                    max_size,
+                    width,
+                    height,
+                    compressed.len()
+                )));
+            }
+
+            Self::set_scaled_size(
+                &mut caesium_parameters,
+                &ResizeArgs::scale(SHRINK_STEP_PERCENT as f32 / 100.0),
+                ImageSize { width, height },
+            );
+        }
+    }
+
+    /// Bisect the quality of `format` for the highest one whose output fits `max_size` bytes,
+    /// the configured quality is the upper bound. The smallest output is returned if none fits.
+    ///
+    /// Formats without a quality parameter (TIFF, or lossless output) are compressed only once.
+    fn search_quality(
+        origin_data: &[u8],
+        caesium_parameters: &mut CSParameters,
+        source_format: OutputFormatTypes,
+        format: OutputFormatTypes,
+        max_size: usize,
+    ) -> Result<(Vec<u8>, Option<u32>), CaesiumError> {
+        let Some(max_quality) = Self::quality_mut(caesium_parameters, format).map(|q| *q) else {
+            let compressed =
+                Self::compress(origin_data, caesium_parameters, source_format, format)?;
+            return Ok((compressed, None));
+        };
+
+        let mut smallest = Self::compress(origin_data, caesium_parameters, source_format, format)?;
+        let mut smallest_quality = max_quality;
+        if smallest.len() <= max_size {
+            return Ok((smallest, Some(max_quality)));
+        }
+
+        let mut best = None;
+        let (mut low, mut high) = (1, max_quality.saturating_sub(1));
+        while low <= high {
+            let quality = (low + high) / 2;
+            if let Some(q) = Self::quality_mut(caesium_parameters, format) {
+                *q = quality;
+            }
+
+            let compressed =
+                Self::compress(origin_data, caesium_parameters, source_format, format)?;
+            if compressed.len() <= max_size {
+                best = Some((compressed, quality));
+                low = quality + 1;
+            } else {
+                if compressed.len() < smallest.len() {
+                    smallest = compressed;
+                    smallest_quality = quality;
+                }
+                high = quality - 1;
+            }
+        }
+
+        let (compressed, quality) = best.unwrap_or((smallest, smallest_quality));
+        if let Some(q) = Self::quality_mut(caesium_parameters, format) {
+            *q = max_quality;
+        }
+
+        Ok((compressed, Some(quality)))
+    }
+
+    /// Bisect the quality of `format` for the lowest one whose output still reaches `min_ssim`,
+    /// the configured quality is the upper bound and is used if even it can't reach `min_ssim`.
+    /// Also returns the SSIM of the chosen output.
+    fn compress_to_ssim(
+        origin_data: &[u8],
+        mut caesium_parameters: CSParameters,
+        source_format: OutputFormatTypes,
+        format: OutputFormatTypes,
+        min_ssim: f64,
+    ) -> Result<(Vec<u8>, Option<u32>, f64), ImageProcessError> {
+        let origin_image = image::load_from_memory(origin_data)?;
+        let mut reference: Option<LumaPlane> = None;
+
+        let mut measure = |caesium_parameters: &CSParameters| {
+            let compressed =
+                Self::compress(origin_data, caesium_parameters, source_format, format)?;
+            let candidate = LumaPlane::from(&image::load_from_memory(&compressed)?);
+
+            // The output may be resized, compare with the origin resized to the same size.
+            let reference = match &reference {
+                Some(reference) if reference.dimensions() == candidate.dimensions() => reference,
+                _ => {
+                    let (width, height) = candidate.dimensions();
+                    reference.insert(LumaPlane::resized(&origin_image, width, height))
+                }
+            };
+
+            let ssim = metrics::ssim(reference, &candidate);
+            Ok::<_, ImageProcessError>((compressed, ssim))
+        };
+
+        let (compressed, ssim) = measure(&caesium_parameters)?;
+        let Some(max_quality) = Self::quality_mut(&mut caesium_parameters, format).map(|q| *q)
+        else {
+            return Ok((compressed, None, ssim));
+        };
+
+        let mut best = (compressed, max_quality, ssim);
+        if ssim < min_ssim {
+            return Ok((best.0, Some(best.1), best.2));
+        }
+
+        let (mut low, mut high) = (1, max_quality.saturating_sub(1));
+        while low <= high {
+            let quality = (low + high) / 2;
+            if let Some(q) = Self::quality_mut(&mut caesium_parameters, format) {
+                *q = quality;
+            }
+
+            let (compressed, ssim) = measure(&caesium_parameters)?;
+            if ssim >= min_ssim {
+                best = (compressed, quality, ssim);
+                high = quality - 1;
+            } else {
+                low = quality + 1;
+            }
         }
 
-        Ok(fs::write(output_file, &compressed).map_err(|e| ImageProcessError::from(e))?)
+        Ok((best.0, Some(best.1), best.2))
+    }
+
+    /// Quality parameter of the output format, `None` if the output is lossless or has no quality.
+    fn quality_mut(
+        caesium_parameters: &mut CSParameters,
+        format: OutputFormatTypes,
+    ) -> Option<&mut u32> {
+        if caesium_parameters.optimize {
+            return None;
+        }
+
+        match format {
+            OutputFormatTypes::Jpeg => Some(&mut caesium_parameters.jpeg.quality),
+            OutputFormatTypes::Png => Some(&mut caesium_parameters.png.quality),
+            OutputFormatTypes::Gif => Some(&mut caesium_parameters.gif.quality),
+            OutputFormatTypes::WebP => Some(&mut caesium_parameters.webp.quality),
+            OutputFormatTypes::Tiff => None,
+        }
+    }
+
+    /// Output size of the image, resolving `0` (auto) sides by the origin aspect ratio.
+    fn scaled_size(caesium_parameters: &CSParameters, image_size: ImageSize) -> (usize, usize) {
+        let (width, height) = (
+            caesium_parameters.width as usize,
+            caesium_parameters.height as usize,
+        );
+
+        // No aspect ratio to resolve by for a broken image without width or height.
+        if image_size.width == 0 || image_size.height == 0 {
+            return (width, height);
+        }
+
+        match (width, height) {
+            (0, 0) => (image_size.width, image_size.height),
+            (width, 0) => (width, image_size.height * width / image_size.width),
+            (0, height) => (image_size.width * height / image_size.height, height),
+            size => size,
+        }
+    }
+
+    fn create_parent_dir(output_file: &Path) -> Result<(), ImageProcessError> {
+        match output_file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+                fs::create_dir_all(parent).map_err(ImageProcessError::from)
+            }
+            _ => Ok(()),
+        }
     }
 
     fn set_scaled_size(
         caesium_parameters: &mut CSParameters,
         resize_args: &ResizeArgs,
-        data: &Vec<u8>,
-    ) -> Result<(), ImageProcessError> {
-        let image_size =
-            imagesize::blob_size(data).map_err(|e| ImageProcessError::new(e.to_string()))?;
-
+        image_size: ImageSize,
+    ) {
         let origin_width = image_size.width;
         let origin_height = image_size.height;
 
@@ -242,9 +1558,259 @@ impl Runner {
                     caesium_parameters.width = origin_width as u32;
                 }
             }
+            ResizeRule::Fit => {
+                let factor = (resize_args.width / origin_width as f32)
+                    .min(resize_args.height / origin_height as f32);
+                caesium_parameters.width = (origin_width as f32 * factor).round() as u32;
+                caesium_parameters.height = (origin_height as f32 * factor).round() as u32;
+            }
+            // The image is already cropped or padded to the aspect ratio of the box.
+            ResizeRule::Cover | ResizeRule::Pad => {
+                caesium_parameters.width = resize_args.width as u32;
+                caesium_parameters.height = resize_args.height as u32;
+            }
             _ => {}
         }
 
-        Ok(())
+        if resize_args.donot_enlarge {
+            let keep_aspect_ratio = match resize_args.rule {
+                ResizeRule::Size => false,
+                ResizeRule::Scale => resize_args.ratio != 0.0,
+                ResizeRule::Width | ResizeRule::Height => resize_args.keep_aspect_ratio,
+                _ => true,
+            };
+            Self::clamp_to_origin_size(
+                caesium_parameters,
+                origin_width,
+                origin_height,
+                keep_aspect_ratio,
+            );
+        }
+    }
+
+    /// Shrink the target size so that it never exceeds the origin size.
+    ///
+    /// With `keep_aspect_ratio`, both edges are scaled by the same factor, so the aspect ratio
+    /// computed by the resize rule is kept, otherwise each edge is limited to the origin edge on
+    /// its own. An edge of `0` means libcaesium derives it from the other edge.
+    fn clamp_to_origin_size(
+        caesium_parameters: &mut CSParameters,
+        origin_width: usize,
+        origin_height: usize,
+        keep_aspect_ratio: bool,
+    ) {
+        let width = caesium_parameters.width as f32;
+        let height = caesium_parameters.height as f32;
+        let origin_width = origin_width as f32;
+        let origin_height = origin_height as f32;
+
+        if keep_aspect_ratio {
+            let factor = match (width > 0.0, height > 0.0) {
+                (true, true) => (origin_width / width).min(origin_height / height),
+                (true, false) => origin_width / width,
+                (false, true) => origin_height / height,
+                (false, false) => return,
+            };
+
+            if factor >= 1.0 {
+                return;
+            }
+
+            caesium_parameters.width = (width * factor).round() as u32;
+            caesium_parameters.height = (height * factor).round() as u32;
+        } else {
+            caesium_parameters.width = width.min(origin_width) as u32;
+            caesium_parameters.height = height.min(origin_height) as u32;
+        }
+
+        // Clamped to the origin size exactly, resizing is unnecessary.
+        let same_width =
+            caesium_parameters.width == 0 || caesium_parameters.width as f32 == origin_width;
+        let same_height =
+            caesium_parameters.height == 0 || caesium_parameters.height as f32 == origin_height;
+        if same_width && same_height {
+            caesium_parameters.width = 0;
+            caesium_parameters.height = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+    use image::{Frame, RgbaImage};
+    use structopt::StructOpt;
+
+    /// Target size of a 1000x500 image resized by `resize_args`, `(0, 0)` means no resize.
+    fn scaled_size(resize_args: &str) -> (u32, u32) {
+        let resize_args: ResizeArgs = resize_args.parse().unwrap();
+        let mut caesium_parameters = CSParameters::new();
+        let image_size = ImageSize {
+            width: 1000,
+            height: 500,
+        };
+        Runner::set_scaled_size(&mut caesium_parameters, &resize_args, image_size);
+        (caesium_parameters.width, caesium_parameters.height)
+    }
+
+    #[test]
+    fn width_keeping_aspect_ratio_is_not_enlarged() {
+        assert_eq!(scaled_size("width:w=2000,donot_enlarge=true"), (0, 0));
+        assert_eq!(scaled_size("width:w=800,donot_enlarge=true"), (800, 0));
+        assert_eq!(scaled_size("width:w=2000"), (2000, 0));
+    }
+
+    #[test]
+    fn width_without_aspect_ratio_clamps_each_edge() {
+        let args = "width:w=2000,keep_aspect_ratio=false,donot_enlarge=true";
+        assert_eq!(scaled_size(args), (0, 0));
+        let args = "width:w=800,keep_aspect_ratio=false,donot_enlarge=true";
+        assert_eq!(scaled_size(args), (800, 500));
+        assert_eq!(
+            scaled_size("width:w=2000,keep_aspect_ratio=false"),
+            (2000, 500)
+        );
+    }
+
+    #[test]
+    fn height_keeping_aspect_ratio_is_not_enlarged() {
+        assert_eq!(scaled_size("height:h=1000,donot_enlarge=true"), (0, 0));
+        assert_eq!(scaled_size("height:h=300,donot_enlarge=true"), (0, 300));
+        assert_eq!(scaled_size("height:h=1000"), (0, 1000));
+    }
+
+    #[test]
+    fn height_without_aspect_ratio_clamps_each_edge() {
+        let args = "height:h=1000,keep_aspect_ratio=false,donot_enlarge=true";
+        assert_eq!(scaled_size(args), (0, 0));
+        let args = "height:h=300,keep_aspect_ratio=false,donot_enlarge=true";
+        assert_eq!(scaled_size(args), (1000, 300));
+        assert_eq!(
+            scaled_size("height:h=1000,keep_aspect_ratio=false"),
+            (1000, 1000)
+        );
+    }
+
+    #[test]
+    fn size_clamps_each_edge() {
+        assert_eq!(
+            scaled_size("size:w=500,h=2000,donot_enlarge=true"),
+            (500, 500)
+        );
+    }
+
+    #[test]
+    fn auto_sides_of_an_image_without_size_are_not_resolved() {
+        let mut caesium_parameters = CSParameters::new();
+        caesium_parameters.width = 100;
+        let image_size = ImageSize {
+            width: 0,
+            height: 50,
+        };
+        assert_eq!(
+            Runner::scaled_size(&caesium_parameters, image_size),
+            (100, 0)
+        );
+    }
+
+    /// EXIF with the orientation tag set to 6 (rotate 90° clockwise).
+    const EXIF_ROTATE_90: [u8; 26] = [
+        0x49, 0x49, 0x2a, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x00, 0x12, 0x01, 0x03, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// A 80x40 JPEG displayed as 40x80 by `EXIF_ROTATE_90`.
+    fn rotated_jpeg() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = JpegEncoder::new(&mut data);
+        encoder.set_exif_metadata(EXIF_ROTATE_90.to_vec()).unwrap();
+        DynamicImage::new_rgb8(80, 40)
+            .write_with_encoder(encoder)
+            .unwrap();
+        data
+    }
+
+    /// Process the image `data` with the command line `args`, returns the output and the report.
+    fn processed(
+        name: &str,
+        data: &[u8],
+        args: &[&str],
+    ) -> Result<(Vec<u8>, FileReport), ImageProcessError> {
+        let dir = std::env::temp_dir().join(format!("imgtool-runner-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (input_file, output_file) = (dir.join(name), dir.join(format!("out-{}", name)));
+        fs::write(&input_file, data).unwrap();
+
+        let mut cli_args = vec!["imgtool", "-i", "in", "-o", "out"];
+        cli_args.extend(args);
+        let run_configuration =
+            RunConfiguration::try_from(CliOptions::from_iter(cli_args)).unwrap();
+        let mut report = FileReport::new(input_file.clone(), output_file.clone());
+        let result =
+            Runner::run_process(&input_file, &output_file, &run_configuration, &mut report);
+        let output = fs::read(&report.output_file);
+
+        fs::remove_file(&input_file).unwrap();
+        let _ = fs::remove_file(&report.output_file);
+        result.map(|_| (output.unwrap(), report))
+    }
+
+    #[test]
+    fn crop_keeps_the_exif_with_keep_metadata() {
+        let data = rotated_jpeg();
+
+        // The crop area is in the dimensions as displayed, the pixels are rotated upright.
+        let args = ["--crop", "0,0,40,60", "--keep-metadata"];
+        let (output, _) = processed("exif.jpg", &data, &args).unwrap();
+        let mut decoder = JpegDecoder::new(Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.dimensions(), (40, 60));
+        let mut exif = EXIF_ROTATE_90.to_vec();
+        exif[18] = 1;
+        assert_eq!(decoder.exif_metadata().unwrap(), Some(exif));
+
+        let args = ["--crop", "0,0,60,20", "--keep-metadata", "--no-auto-orient"];
+        let (output, _) = processed("stored.jpg", &data, &args).unwrap();
+        let mut decoder = JpegDecoder::new(Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.dimensions(), (60, 20));
+        assert_eq!(
+            decoder.exif_metadata().unwrap(),
+            Some(EXIF_ROTATE_90.to_vec())
+        );
+
+        let (output, _) = processed("no-exif.jpg", &data, &["--crop", "0,0,40,60"]).unwrap();
+        let mut decoder = JpegDecoder::new(Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.dimensions(), (40, 60));
+        assert_eq!(decoder.exif_metadata().unwrap(), None);
+    }
+
+    #[test]
+    fn crop_rejects_animated_images() {
+        let mut data = Vec::new();
+        let frame = || Frame::new(RgbaImage::new(20, 20));
+        GifEncoder::new(&mut data)
+            .encode_frames([frame(), frame()])
+            .unwrap();
+
+        let result = processed("animated.gif", &data, &["--crop", "0,0,10,10", "-t", "png"]);
+        assert!(matches!(
+            result,
+            Err(ImageProcessError::InvalidArguments(_))
+        ));
+    }
+
+    #[test]
+    fn output_dimensions_of_a_rotated_jpeg_are_as_displayed() {
+        for args in [&[][..], &["--keep-metadata"]] {
+            let (output, report) = processed("rotated.jpg", &rotated_jpeg(), args).unwrap();
+            assert_eq!(report.origin_dimensions, Some((40, 80)), "{:?}", args);
+            assert_eq!(report.output_dimensions, Some((40, 80)), "{:?}", args);
+
+            // The pixels are rotated unless the orientation tag is kept.
+            let stored = JpegDecoder::new(Cursor::new(&output)).unwrap().dimensions();
+            let expected = if args.is_empty() { (40, 80) } else { (80, 40) };
+            assert_eq!(stored, expected, "{:?}", args);
+        }
     }
 }
@@ -0,0 +1,280 @@
+use crate::error::ImageProcessError;
+use crate::options::{Color, CropArgs, Gravity};
+use image::metadata::Orientation;
+use image::{DynamicImage, GenericImageView, ImageDecoder, ImageReader, Rgba, RgbaImage, imageops};
+use imagesize::ImageSize;
+use std::io::{BufRead, Seek};
+
+/// Max channel difference from the border color of `--trim` without a tolerance.
+pub const DEFAULT_TRIM_TOLERANCE: u8 = 10;
+
+/// Size of the area with the aspect ratio of `width` x `height` that just fits inside the image
+/// of `(origin_width, origin_height)` (`fit_inside`) or just covers it.
+fn aspect_size(
+    (origin_width, origin_height): (u32, u32),
+    width: u32,
+    height: u32,
+    fit_inside: bool,
+) -> (u32, u32) {
+    let wider =
+        u64::from(origin_width) * u64::from(height) > u64::from(origin_height) * u64::from(width);
+
+    // Keep the side that limits the area, derive the other one by the aspect ratio.
+    let size = if wider != fit_inside {
+        let scaled = u64::from(origin_width) * u64::from(height) / u64::from(width);
+        (origin_width, scaled as u32)
+    } else {
+        let scaled = u64::from(origin_height) * u64::from(width) / u64::from(height);
+        (scaled as u32, origin_height)
+    };
+
+    (size.0.max(1), size.1.max(1))
+}
+
+/// Crop the image to the aspect ratio of `width` x `height`, the kept area is placed by `gravity`.
+pub fn crop_to_aspect(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    gravity: Gravity,
+) -> DynamicImage {
+    let (crop_width, crop_height) = aspect_size(image.dimensions(), width, height, true);
+    let (x, y) = gravity.offset(image.width() - crop_width, image.height() - crop_height);
+
+    image.crop_imm(x, y, crop_width, crop_height)
+}
+
+/// Pad the image to the aspect ratio of `width` x `height` with `background`, the image is placed
+/// by `gravity`.
+pub fn pad_to_aspect(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    gravity: Gravity,
+    background: Color,
+) -> DynamicImage {
+    let (canvas_width, canvas_height) = aspect_size(image.dimensions(), width, height, false);
+    let (x, y) = gravity.offset(canvas_width - image.width(), canvas_height - image.height());
+
+    let mut canvas = RgbaImage::from_pixel(canvas_width, canvas_height, Rgba(background.0));
+    imageops::overlay(&mut canvas, &image.to_rgba8(), i64::from(x), i64::from(y));
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Area `(x, y, width, height)` kept by `--crop` in the image of `(image_width, image_height)`.
+pub fn crop_area(
+    crop: &CropArgs,
+    (image_width, image_height): (u32, u32),
+) -> Result<(u32, u32, u32, u32), ImageProcessError> {
+    match *crop {
+        CropArgs::Rect {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            if u64::from(x) + u64::from(width) > u64::from(image_width)
+                || u64::from(y) + u64::from(height) > u64::from(image_height)
+            {
+                return Err(ImageProcessError::InvalidArguments(format!(
+                    "crop area {}x{} at {},{} is outside of the image {}x{}",
+                    width, height, x, y, image_width, image_height
+                )));
+            }
+
+            Ok((x, y, width, height))
+        }
+        CropArgs::Insets([top, right, bottom, left]) => {
+            let cut = |size: u32, percent: f32| (size as f32 * percent / 100.0).round() as u32;
+            let (x, y) = (cut(image_width, left), cut(image_height, top));
+            let width = image_width.saturating_sub(x + cut(image_width, right));
+            let height = image_height.saturating_sub(y + cut(image_height, bottom));
+
+            Ok((x, y, width.max(1), height.max(1)))
+        }
+        CropArgs::Aspect {
+            width,
+            height,
+            gravity,
+        } => {
+            let (crop_width, crop_height) =
+                aspect_size((image_width, image_height), width, height, true);
+            let (x, y) = gravity.offset(image_width - crop_width, image_height - crop_height);
+
+            Ok((x, y, crop_width, crop_height))
+        }
+    }
+}
+
+/// Pixels `[top, right, bottom, left]` of the borders in the color of the top-left pixel, a pixel
+/// belongs to the border if all channels differ by at most `tolerance`, or if both are fully
+/// transparent. `None` if the whole image is the border.
+pub fn trim_borders(image: &DynamicImage, tolerance: u8) -> Option<[u32; 4]> {
+    let pixels = image.to_rgba8();
+    let (width, height) = pixels.dimensions();
+    let border = *pixels.get_pixel(0, 0);
+
+    let is_border = |x: u32, y: u32| {
+        let pixel = pixels.get_pixel(x, y);
+        (border[3] == 0 && pixel[3] == 0)
+            || pixel
+                .0
+                .iter()
+                .zip(border.0)
+                .all(|(channel, border_channel)| channel.abs_diff(border_channel) <= tolerance)
+    };
+    let is_border_row = |y: u32| (0..width).all(|x| is_border(x, y));
+
+    let top = (0..height).find(|&y| !is_border_row(y))?;
+    let bottom = (top..height).rev().find(|&y| !is_border_row(y))?;
+
+    let is_border_column = |x: u32| (top..=bottom).all(|y| is_border(x, y));
+    let left = (0..width).find(|&x| !is_border_column(x))?;
+    let right = (left..width).rev().find(|&x| !is_border_column(x))?;
+
+    Some([top, width - 1 - right, height - 1 - bottom, left])
+}
+
+/// EXIF orientation of the image, `None` if it's upright or the orientation can't be read.
+pub fn read_orientation(reader: impl BufRead + Seek) -> Option<Orientation> {
+    let mut decoder = ImageReader::new(reader)
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+
+    decoder
+        .orientation()
+        .ok()
+        .filter(|orientation| *orientation != Orientation::NoTransforms)
+}
+
+/// Size of the image as displayed, orientations rotating by 90 degrees swap width and height.
+pub fn oriented_size(image_size: ImageSize, orientation: Option<Orientation>) -> ImageSize {
+    match orientation {
+        Some(
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH,
+        ) => ImageSize {
+            width: image_size.height,
+            height: image_size.width,
+        },
+        _ => image_size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `width` x `height` image, black but the top left pixel white.
+    fn image(width: u32, height: u32) -> DynamicImage {
+        let mut image = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+        image.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        DynamicImage::ImageRgba8(image)
+    }
+
+    #[test]
+    fn aspect_size_fits_inside_or_covers_the_image() {
+        let wide = (400, 100);
+        assert_eq!(aspect_size(wide, 1, 1, true), (100, 100));
+        assert_eq!(aspect_size(wide, 1, 1, false), (400, 400));
+        assert_eq!(aspect_size(wide, 400, 100, true), (400, 100));
+
+        let tall = (100, 400);
+        assert_eq!(aspect_size(tall, 2, 1, true), (100, 50));
+        assert_eq!(aspect_size(tall, 2, 1, false), (800, 400));
+    }
+
+    #[test]
+    fn aspect_size_is_at_least_one_pixel() {
+        assert_eq!(aspect_size((1000, 1), 1, 1000, true), (1, 1));
+    }
+
+    #[test]
+    fn crop_to_aspect_keeps_the_area_at_gravity() {
+        let cropped = crop_to_aspect(&image(400, 100), 1, 1, Gravity::NorthWest);
+        assert_eq!(cropped.dimensions(), (100, 100));
+        assert_eq!(cropped.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+
+        let cropped = crop_to_aspect(&image(400, 100), 1, 1, Gravity::Center);
+        assert_eq!(cropped.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn pad_to_aspect_places_the_image_at_gravity() {
+        let background = Color([255, 0, 0, 255]);
+        let padded = pad_to_aspect(&image(400, 100), 1, 1, Gravity::South, background);
+        assert_eq!(padded.dimensions(), (400, 400));
+        assert_eq!(padded.get_pixel(0, 0), Rgba(background.0));
+        assert_eq!(padded.get_pixel(0, 300), Rgba([255, 255, 255, 255]));
+        assert_eq!(padded.get_pixel(399, 399), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn crop_area_resolves_the_crop_args() {
+        let area = |crop: &str| crop_area(&crop.parse().unwrap(), (400, 200));
+
+        assert_eq!(area("10,20,100,50").unwrap(), (10, 20, 100, 50));
+        assert_eq!(area("0,0,400,200").unwrap(), (0, 0, 400, 200));
+        assert_eq!(area("10%,25%").unwrap(), (100, 20, 200, 160));
+        assert_eq!(area("1:1,gravity=east").unwrap(), (200, 0, 200, 200));
+        assert_eq!(area("1:1").unwrap(), (100, 0, 200, 200));
+    }
+
+    #[test]
+    fn crop_area_outside_of_the_image_is_an_error() {
+        let crop = "300,0,101,200".parse().unwrap();
+        assert!(matches!(
+            crop_area(&crop, (400, 200)),
+            Err(ImageProcessError::InvalidArguments(_))
+        ));
+    }
+
+    /// A `width` x `height` image of `border` with a `content` area at `(x, y)`.
+    fn bordered(
+        (width, height): (u32, u32),
+        border: [u8; 4],
+        (x, y, content_width, content_height): (u32, u32, u32, u32),
+        content: [u8; 4],
+    ) -> DynamicImage {
+        let mut image = RgbaImage::from_pixel(width, height, Rgba(border));
+        for (px, py, pixel) in image.enumerate_pixels_mut() {
+            if (x..x + content_width).contains(&px) && (y..y + content_height).contains(&py) {
+                *pixel = Rgba(content);
+            }
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+
+    #[test]
+    fn trim_borders_finds_each_edge() {
+        let image = bordered((100, 80), [255; 4], (10, 5, 60, 50), [0, 0, 0, 255]);
+        assert_eq!(trim_borders(&image, 10), Some([5, 30, 25, 10]));
+    }
+
+    #[test]
+    fn trim_borders_applies_the_tolerance() {
+        let image = bordered((20, 20), [255; 4], (5, 5, 10, 10), [240, 240, 240, 255]);
+        assert_eq!(trim_borders(&image, 10), Some([5, 5, 5, 5]));
+        assert_eq!(trim_borders(&image, 15), None);
+    }
+
+    #[test]
+    fn trim_borders_treats_transparent_pixels_alike() {
+        let mut image = bordered((20, 20), [0, 0, 0, 0], (2, 3, 4, 5), [255; 4]).to_rgba8();
+        image.put_pixel(19, 19, Rgba([255, 0, 0, 0]));
+        let image = DynamicImage::ImageRgba8(image);
+        assert_eq!(trim_borders(&image, 0), Some([3, 14, 12, 2]));
+    }
+
+    #[test]
+    fn trim_borders_of_an_image_without_border() {
+        assert_eq!(trim_borders(&image(10, 10), 10), Some([0, 0, 0, 0]));
+        let uniform = bordered((10, 10), [255; 4], (0, 0, 0, 0), [255; 4]);
+        assert_eq!(trim_borders(&uniform, 10), None);
+    }
+}